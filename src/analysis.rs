@@ -0,0 +1,250 @@
+use crate::disassembler::*;
+use crate::memory::*;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::ops::Range;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Skip, // Taken when the skip condition holds
+    Call,
+}
+
+#[derive(Clone, Copy)]
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+// Instructions that run one after the other, only the last one can go anywhere else
+pub struct Block {
+    pub start: usize,
+    pub end: usize, // Exclusive
+    pub edges: Vec<Edge>,
+    pub subroutine: usize, // Entry of the subroutine the block belongs to
+}
+
+// Something the analysis couldn't follow or that looks suspicious
+pub struct Finding {
+    pub address: usize,
+    pub message: String,
+}
+
+pub struct ControlFlowGraph {
+    pub entry_point: usize,
+    pub blocks: BTreeMap<usize, Block>,
+    pub subroutines: BTreeSet<usize>,
+    pub findings: Vec<Finding>,
+}
+
+fn opcode_at(mem: &Memory, address: usize) -> u16 {
+    ((mem.peek(address) as u16) << 8) | mem.peek(address + 1) as u16
+}
+
+// Where an instruction can go next, and whether it has to be the last one of its block
+fn instruction_edges(address: usize, opcode: u16) -> (Vec<Edge>, bool) {
+    let next = Edge { target: address + 2, kind: EdgeKind::Fallthrough };
+    let target = (opcode & 0x0FFF) as usize;
+    match opcode & 0xF000 {
+        0x0000 if opcode == 0x00EE || opcode == 0x00FD => (vec![], true), // Return, exit
+        0x1000 => (vec![Edge { target, kind: EdgeKind::Jump }], true),
+        0x2000 => (vec![Edge { target, kind: EdgeKind::Call }, next], true),
+        0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xE000 => (vec![next, Edge { target: address + 4, kind: EdgeKind::Skip }], true),
+        0xB000 => (vec![], true), // Computed, can't be followed
+        _ => (vec![next], false),
+    }
+}
+
+// Follows every path from the entry point through the ROM. Only code that is reachable without
+// knowing register values is found, computed jumps are reported instead of guessed
+pub fn analyze(mem: &Memory, entry_point: usize, rom: Range<usize>) -> ControlFlowGraph {
+    let mut findings = Vec::new();
+    let mut instructions = BTreeSet::new();
+    let mut leaders = BTreeSet::from([entry_point]);
+    let mut subroutines = BTreeSet::from([entry_point]);
+    let mut visited = HashSet::new();
+    let mut pending = VecDeque::from([entry_point]);
+    while let Some(address) = pending.pop_front() {
+        if !visited.insert(address) {
+            continue;
+        }
+        if !rom.contains(&address) || !rom.contains(&(address + 1)) {
+            findings.push(Finding { address, message: "entry point is outside of the ROM".to_string() });
+            continue;
+        }
+        instructions.insert(address);
+
+        let opcode = opcode_at(mem, address);
+        match opcode & 0xF000 {
+            0xB000 => findings.push(Finding { address, message: format!("computed jump from {:03X} on, targets unknown", opcode & 0x0FFF) }),
+            0x0000 if !matches!(opcode, 0x00E0 | 0x00EE | 0x00FB..=0x00FF) && opcode & 0xFFF0 != 0x00C0 => {
+                findings.push(Finding { address, message: format!("calls machine code at {:03X}", opcode & 0x0FFF) })
+            }
+            _ => {}
+        }
+        let (edges, ends_block) = instruction_edges(address, opcode);
+        for edge in &edges {
+            if !rom.contains(&edge.target) || !rom.contains(&(edge.target + 1)) {
+                findings.push(Finding { address, message: format!("goes to {:03X}, outside of the ROM", edge.target) });
+                continue;
+            }
+            if edge.kind == EdgeKind::Call {
+                subroutines.insert(edge.target);
+            }
+            if ends_block {
+                leaders.insert(edge.target);
+            }
+            pending.push_back(edge.target);
+        }
+    }
+
+    // Cut the instructions into blocks at every leader and after every branch
+    let mut blocks = BTreeMap::new();
+    let instruction_bytes: HashSet<usize> = instructions.iter().flat_map(|address| [*address, address + 1]).collect();
+    for &start in leaders.iter().filter(|leader| instructions.contains(leader)) {
+        let mut address = start;
+        let mut index_register = None; // Value of I when it was set by an ANNN earlier in the block
+        loop {
+            let opcode = opcode_at(mem, address);
+            let x = ((opcode >> 8) & 0xF) as usize;
+            match opcode & 0xF0FF {
+                _ if opcode & 0xF000 == 0xA000 => index_register = Some((opcode & 0x0FFF) as usize),
+                0xF033 | 0xF055 => {
+                    let length = if opcode & 0xF0FF == 0xF033 { 3 } else { x + 1 };
+                    if let Some(index) = index_register.filter(|index| (*index..*index + length).any(|byte| instruction_bytes.contains(&byte))) {
+                        findings.push(Finding { address, message: format!("writes into code at {:03X}, self-modifying", index) });
+                    }
+                    index_register = None;
+                }
+                0xF01E | 0xF065 => index_register = None,
+                _ => {}
+            }
+
+            let (edges, ends_block) = instruction_edges(address, opcode);
+            let next = address + 2;
+            if ends_block || !instructions.contains(&next) || leaders.contains(&next) {
+                let edges = if ends_block { edges } else { edges.into_iter().filter(|edge| instructions.contains(&edge.target)).collect() };
+                blocks.insert(start, Block { start, end: next, edges, subroutine: entry_point });
+                break;
+            }
+            address = next;
+        }
+    }
+
+    // A block belongs to the first subroutine it's reached from without following calls. Code
+    // running into another subroutine's entry point leaves that one alone
+    let mut assigned = HashSet::new();
+    for &subroutine in &subroutines {
+        let mut pending = VecDeque::from([subroutine]);
+        while let Some(start) = pending.pop_front() {
+            if (start != subroutine && subroutines.contains(&start)) || !blocks.contains_key(&start) || !assigned.insert(start) {
+                continue;
+            }
+            let block = blocks.get_mut(&start).unwrap();
+            block.subroutine = subroutine;
+            pending.extend(block.edges.iter().filter(|edge| edge.kind != EdgeKind::Call).map(|edge| edge.target));
+        }
+    }
+
+    findings.sort_by_key(|finding| finding.address);
+    ControlFlowGraph { entry_point, blocks, subroutines, findings }
+}
+
+impl ControlFlowGraph {
+    // Graphviz DOT with a cluster per subroutine. Calls are dashed, skips dotted, and blocks with
+    // findings are drawn in red with the findings spelled out
+    pub fn to_dot(&self, mem: &Memory) -> String {
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph cfg {{");
+        let _ = writeln!(dot, "  node [shape=box, fontname=\"monospace\"];");
+        for &subroutine in &self.subroutines {
+            let label = if subroutine == self.entry_point { format!("entry {:03X}", subroutine) } else { format!("subroutine {:03X}", subroutine) };
+            let _ = writeln!(dot, "  subgraph cluster_{:03X} {{", subroutine);
+            let _ = writeln!(dot, "    label=\"{}\";", label);
+            for block in self.blocks.values().filter(|block| block.subroutine == subroutine) {
+                let mut text = String::new();
+                for address in (block.start..block.end).step_by(2) {
+                    let _ = write!(text, "{:03X}: {}\\l", address, disassemble(opcode_at(mem, address)));
+                }
+                let findings: Vec<&Finding> = self.findings.iter().filter(|finding| (block.start..block.end).contains(&finding.address)).collect();
+                for finding in &findings {
+                    let _ = write!(text, "! {:03X}: {}\\l", finding.address, finding.message);
+                }
+                let color = if findings.is_empty() { "" } else { ", color=red" };
+                let _ = writeln!(dot, "    b{:03X} [label=\"{}\"{}];", block.start, text, color);
+            }
+            let _ = writeln!(dot, "  }}");
+        }
+        for block in self.blocks.values() {
+            for edge in &block.edges {
+                if !self.blocks.contains_key(&edge.target) {
+                    continue;
+                }
+                let style = match edge.kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Skip => " [label=\"skip\", style=dotted]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                };
+                let _ = writeln!(dot, "  b{:03X} -> b{:03X}{};", block.start, edge.target, style);
+            }
+        }
+        let _ = writeln!(dot, "}}");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(program: &[u16]) -> (Memory, Range<usize>) {
+        let mut mem = Memory::new(0x1000, MemoryPolicy::Wrap);
+        for (i, opcode) in program.iter().enumerate() {
+            mem.poke(0x200 + i * 2, (opcode >> 8) as u8);
+            mem.poke(0x201 + i * 2, *opcode as u8);
+        }
+        (mem, 0x200..0x200 + program.len() * 2)
+    }
+
+    #[test]
+    fn blocks_and_subroutines() {
+        // Calls a subroutine that overwrites the entry point, then loops on a skip until it exits
+        let (mem, rom) = load(&[0x2208, 0x3005, 0x1202, 0x00FD, 0xA200, 0xF055, 0x00EE]);
+        let cfg = analyze(&mem, 0x200, rom);
+
+        let blocks: Vec<(usize, usize, usize)> = cfg.blocks.values().map(|block| (block.start, block.end, block.subroutine)).collect();
+        assert_eq!(blocks, vec![(0x200, 0x202, 0x200), (0x202, 0x204, 0x200), (0x204, 0x206, 0x200), (0x206, 0x208, 0x200), (0x208, 0x20E, 0x208)]);
+        assert_eq!(cfg.subroutines, BTreeSet::from([0x200, 0x208]));
+
+        let edges: Vec<(usize, bool)> = cfg.blocks[&0x202].edges.iter().map(|edge| (edge.target, edge.kind == EdgeKind::Skip)).collect();
+        assert_eq!(edges, vec![(0x204, false), (0x206, true)]);
+        assert!(cfg.blocks[&0x204].edges.iter().all(|edge| edge.target == 0x202 && edge.kind == EdgeKind::Jump));
+        assert!(cfg.blocks[&0x206].edges.is_empty());
+
+        assert_eq!(cfg.findings.len(), 1);
+        assert_eq!((cfg.findings[0].address, cfg.findings[0].message.as_str()), (0x20A, "writes into code at 200, self-modifying"));
+    }
+
+    #[test]
+    fn unfollowable_code_is_reported() {
+        let (mem, rom) = load(&[0x3000, 0x1300, 0xB210, 0x0123]);
+        let cfg = analyze(&mem, 0x200, rom);
+        let findings: Vec<(usize, &str)> = cfg.findings.iter().map(|finding| (finding.address, finding.message.as_str())).collect();
+        assert_eq!(findings, vec![(0x202, "goes to 300, outside of the ROM"), (0x204, "computed jump from 210 on, targets unknown")]);
+        // The machine code call is never reached
+        assert!(!cfg.blocks.contains_key(&0x206));
+    }
+
+    #[test]
+    fn dot_export() {
+        let (mem, rom) = load(&[0x2204, 0x1200, 0x00EE]);
+        let dot = analyze(&mem, 0x200, rom).to_dot(&mem);
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("subgraph cluster_204"));
+        assert!(dot.contains("b200 -> b204 [label=\"call\", style=dashed];"));
+        assert!(dot.contains("b202 -> b200 [label=\"jump\"];"));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::memory::*;
+use crate::romdb::parse_address;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// How a byte has to compare to the last snapshot to stay a candidate
+#[derive(Clone, Copy)]
+pub enum Comparison {
+    Equal(u8), // Equal to a given value, whatever it was before
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Comparison {
+    pub fn from_name(name: &str, value: Option<u8>) -> Option<Self> {
+        match name {
+            "equal" => value.map(Comparison::Equal),
+            "changed" => Some(Comparison::Changed),
+            "unchanged" => Some(Comparison::Unchanged),
+            "increased" => Some(Comparison::Increased),
+            "decreased" => Some(Comparison::Decreased),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, before: u8, now: u8) -> bool {
+        match self {
+            Comparison::Equal(value) => now == *value,
+            Comparison::Changed => now != before,
+            Comparison::Unchanged => now == before,
+            Comparison::Increased => now > before,
+            Comparison::Decreased => now < before,
+        }
+    }
+}
+
+// Narrows memory down to the addresses holding something, like a lives counter, by comparing
+// snapshots taken as the game goes on
+pub struct CheatSearch {
+    snapshot: Vec<u8>,
+    pub candidates: Vec<usize>,
+}
+
+impl CheatSearch {
+    // Every address starts out as a candidate
+    pub fn new(mem: &Memory) -> Self {
+        CheatSearch {
+            snapshot: (0..mem.size()).map(|address| mem.peek(address)).collect(),
+            candidates: (0..mem.size()).collect(),
+        }
+    }
+
+    // Keeps the candidates that compare the right way and takes a new snapshot
+    pub fn filter(&mut self, mem: &Memory, comparison: Comparison) {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|address| comparison.matches(snapshot[*address], mem.peek(*address)));
+        self.snapshot = (0..mem.size()).map(|address| mem.peek(address)).collect();
+    }
+}
+
+// Frozen cheats are written back every frame, the others once when they're added
+#[derive(Clone)]
+pub struct Cheat {
+    pub address: usize,
+    pub value: u8,
+    pub freeze: bool,
+    pub description: String,
+}
+
+impl Cheat {
+    pub fn apply(&self, mem: &mut Memory) {
+        mem.poke(self.address, self.value);
+    }
+}
+
+// Each ROM gets its own list, named after its hash
+pub fn cheat_path(cheat_dir: &str, rom_hash: &str) -> PathBuf {
+    Path::new(cheat_dir).join(format!("{}.cht", rom_hash))
+}
+
+// One cheat per line: `<address> <value> freeze|patch [description]`, both numbers in hex.
+// A ROM without a list just has no cheats
+pub fn load_cheats(path: &Path) -> io::Result<Vec<Cheat>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut cheats = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: expected <address> <value> freeze|patch [description]", path.display(), line_number + 1));
+        let mut fields = line.splitn(4, char::is_whitespace);
+        let address = fields.next().and_then(parse_address).ok_or_else(invalid)?;
+        let value = fields.next().and_then(|value| u8::from_str_radix(value.trim_start_matches("0x"), 16).ok()).ok_or_else(invalid)?;
+        let freeze = match fields.next() {
+            Some("freeze") => true,
+            Some("patch") => false,
+            _ => return Err(invalid()),
+        };
+        let description = fields.next().unwrap_or("").trim().to_string();
+        cheats.push(Cheat { address, value, freeze, description });
+    }
+    Ok(cheats)
+}
+
+pub fn save_cheats(path: &Path, cheats: &[Cheat]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lines: Vec<String> = cheats
+        .iter()
+        .map(|cheat| format!("{:03X} {:02X} {} {}", cheat.address, cheat.value, if cheat.freeze { "freeze" } else { "patch" }, cheat.description).trim_end().to_string())
+        .collect();
+    fs::write(path, lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_narrows_down_the_counter() {
+        let mut mem = Memory::new(16, MemoryPolicy::Wrap);
+        mem.poke(3, 5);
+        mem.poke(7, 5);
+        mem.poke(9, 1);
+        let mut search = CheatSearch::new(&mem);
+        search.filter(&mem, Comparison::Equal(5));
+        assert_eq!(search.candidates, vec![3, 7]);
+
+        // A life is lost, only one of them goes down
+        mem.poke(3, 4);
+        mem.poke(9, 0);
+        search.filter(&mem, Comparison::Decreased);
+        assert_eq!(search.candidates, vec![3]);
+        search.filter(&mem, Comparison::Unchanged);
+        assert_eq!(search.candidates, vec![3]);
+        search.filter(&mem, Comparison::Changed);
+        assert!(search.candidates.is_empty());
+    }
+
+    #[test]
+    fn comparisons_by_name() {
+        assert!(matches!(Comparison::from_name("equal", Some(3)), Some(Comparison::Equal(3))));
+        assert!(Comparison::from_name("equal", None).is_none());
+        assert!(matches!(Comparison::from_name("increased", None), Some(Comparison::Increased)));
+        assert!(Comparison::from_name("bigger", None).is_none());
+    }
+
+    #[test]
+    fn cheat_files_round_trip() {
+        let dir = std::env::temp_dir().join(format!("chip8-cheat-test-{}", std::process::id()));
+        let path = cheat_path(dir.to_str().unwrap(), "abc");
+        assert!(load_cheats(&path).unwrap().is_empty());
+
+        let cheats = vec![
+            Cheat { address: 0x3A0, value: 0x09, freeze: true, description: "Infinite lives".to_string() },
+            Cheat { address: 0x200, value: 0xFF, freeze: false, description: String::new() },
+        ];
+        save_cheats(&path, &cheats).unwrap();
+        let loaded = load_cheats(&path);
+        fs::write(&path, "# comment\n200 zz patch\n").unwrap();
+        let invalid = load_cheats(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!((loaded[0].address, loaded[0].value, loaded[0].freeze, loaded[0].description.as_str()), (0x3A0, 0x09, true, "Infinite lives"));
+        assert_eq!((loaded[1].address, loaded[1].value, loaded[1].freeze, loaded[1].description.as_str()), (0x200, 0xFF, false, ""));
+        assert!(invalid.err().unwrap().to_string().contains(":2:"));
+    }
+}
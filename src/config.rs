@@ -0,0 +1,224 @@
+use crate::gamepad::GamepadMapping;
+use crate::memory::MemoryPolicy;
+use crate::patch::Patch;
+use crate::platform::Platform;
+use crate::renderer::Persistence;
+use crate::romdb::{parse_address, parse_stack_depth};
+use crate::rng::*;
+
+#[derive(Clone)]
+pub struct Config {
+    pub rom_path: String,
+    pub platform: Option<Platform>, // Detected from the ROM when not given
+    pub load_address: Option<usize>, // Platform's default when not given
+    pub entry_point: Option<usize>,  // Load address when not given
+    pub stack_depth: Option<usize>,  // Platform's default when not given
+    pub vip_stack: bool,
+    pub rom_db_path: Option<String>,
+    pub patches: Vec<Patch>,
+    pub instructions_per_frame: u32,
+    pub memory_policy: MemoryPolicy,
+    pub display_wait: bool,
+    pub seed: Option<u64>,
+    pub rng: RngKind,
+    pub palette: Option<String>,
+    pub palette_file: Option<String>,
+    pub persistence: Persistence,
+    pub integer_scaling: bool,
+    pub fullscreen: bool,
+    pub keypad: bool,
+    pub terminal: bool,
+    pub watch: bool,
+    pub decay_rate: f32,
+    pub record_path: Option<String>,
+    pub profile_path: Option<String>,
+    pub coverage_path: Option<String>,
+    pub cfg_path: Option<String>,
+    pub gdb_port: Option<u16>,
+    pub rpc_port: Option<u16>,
+    pub script_path: Option<String>,
+    pub cheat_dir: String,
+    pub data_dir: String,
+    pub gamepad: Option<GamepadMapping>, // ROM database's or the default one when not given
+    pub use_gamepad: bool,
+    pub replay_path: Option<String>,
+}
+
+pub const USAGE: &str = "<path to ROM> [options]
+
+Options:
+    --platform <name>  chip8, eti660, dream6800, schip or xochip (default detected from the ROM)
+    --load-address <addr>
+                       Hex address the ROM is loaded at (default 200, 600 on the ETI-660)
+    --entry-point <addr>
+                       Hex address execution starts at (default the load address)
+    --stack-depth <n>  Nested subroutine calls allowed (default 12, 16 on SUPER-CHIP and XO-CHIP)
+    --vip-stack        Keep the call stack in memory at 0xEA0 like the COSMAC VIP
+    --rom-db <file>    ROM database with settings per ROM (default rom-db.txt if it exists)
+    --patch <patch>    Apply an IPS or BPS file, or <addr>=<hex bytes>, to the ROM as it's
+                       loaded. Can be given more than once
+    --ipf <n>          Instructions executed per 60 Hz frame (default 10)
+    --memory-policy <policy>
+                       Out of range memory accesses: wrap, fault or break (default fault)
+    --display-wait     Sprite drawing waits for the next frame, like the COSMAC VIP
+    --seed <n>         Seed for the CXNN random number generator (default random)
+    --rng <kind>       Random number generator: seeded or vip (default seeded)
+    --palette <name>   Color palette: classic, green, amber, hp48, octo or a custom one
+    --palette-file <file>
+                       Load custom palettes from a file, see the README
+    --persistence <mode>
+                       Anti-flicker mode: off, decay or blend (default off)
+    --decay-rate <n>   Brightness lost per frame by fading pixels, 0 to 1 (default 0.35)
+    --integer-scaling  Only scale the display by whole multiples
+    --fullscreen       Start in fullscreen
+    --keypad           Show a clickable hex keypad next to the display
+    --terminal         Render in the terminal instead of opening a window
+    --watch            Reload the ROM whenever the file changes
+    --record <file>    Record keypad input to a movie file
+    --replay <file>    Play back a movie file recorded with --record
+    --profile <file>   Write where the ROM spent its time to a file when quitting, as JSON if
+                       the name ends in .json
+    --coverage <file>  Write an annotated listing of what the ROM executed, read and wrote to a
+                       file when quitting, with an lcov summary in <file>.info
+    --gdb <port>       Wait for a GDB remote debugger on a local port before running
+    --rpc <port>       Accept JSON-RPC remote control connections on a local port
+    --script <file>    Run a Rhai script with callbacks on frames, instructions, writes and keys
+    --cheat-dir <dir>  Where cheat lists are kept, one per ROM (default cheats)
+    --data-dir <dir>   Where SUPER-CHIP RPL flags are kept, one file per ROM (default data)
+    --gamepad <mapping>
+                       Hex keys pressed by gamepad controls, e.g. up:2,down:8,a:5
+                       (default up:5,down:8,left:7,right:9,a:6,b:4)
+    --no-gamepad       Ignore gamepads
+    --cfg <file>       Write the ROM's control flow graph as Graphviz DOT and exit without running it";
+
+impl Config {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        if args.len() < 2 {
+            return Err("Missing ROM path".to_string());
+        }
+
+        let mut config = Config {
+            rom_path: args[1].clone(),
+            platform: None,
+            load_address: None,
+            entry_point: None,
+            stack_depth: None,
+            vip_stack: false,
+            rom_db_path: None,
+            patches: Vec::new(),
+            instructions_per_frame: 10,
+            memory_policy: MemoryPolicy::Fault,
+            display_wait: false,
+            seed: None,
+            rng: RngKind::Seeded,
+            palette: None,
+            palette_file: None,
+            persistence: Persistence::Off,
+            integer_scaling: false,
+            fullscreen: false,
+            keypad: false,
+            terminal: false,
+            watch: false,
+            decay_rate: 0.35,
+            record_path: None,
+            profile_path: None,
+            coverage_path: None,
+            cfg_path: None,
+            gdb_port: None,
+            rpc_port: None,
+            script_path: None,
+            cheat_dir: "cheats".to_string(),
+            data_dir: "data".to_string(),
+            gamepad: None,
+            use_gamepad: true,
+            replay_path: None,
+        };
+
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let mut next_value = || options.next().map(String::as_str).ok_or_else(|| format!("Missing value for {}", option));
+            match option.as_str() {
+                "--platform" => {
+                    let value = next_value()?;
+                    config.platform = Some(Platform::from_name(value).ok_or_else(|| format!("Unknown platform: {}", value))?);
+                }
+                "--ipf" => {
+                    let value = next_value()?;
+                    config.instructions_per_frame = value.parse().map_err(|_| format!("Invalid instructions per frame: {}", value))?;
+                }
+                "--load-address" => {
+                    let value = next_value()?;
+                    config.load_address = Some(parse_address(value).ok_or_else(|| format!("Invalid load address: {}", value))?);
+                }
+                "--entry-point" => {
+                    let value = next_value()?;
+                    config.entry_point = Some(parse_address(value).ok_or_else(|| format!("Invalid entry point: {}", value))?);
+                }
+                "--stack-depth" => {
+                    let value = next_value()?;
+                    config.stack_depth = Some(parse_stack_depth(value).ok_or_else(|| format!("Invalid stack depth: {}", value))?);
+                }
+                "--vip-stack" => config.vip_stack = true,
+                "--rom-db" => config.rom_db_path = Some(next_value()?.to_string()),
+                "--patch" => config.patches.push(Patch::parse(next_value()?)?),
+                "--memory-policy" => {
+                    let value = next_value()?;
+                    config.memory_policy = MemoryPolicy::from_name(value).ok_or_else(|| format!("Unknown memory policy: {}", value))?;
+                }
+                "--seed" => {
+                    let value = next_value()?;
+                    config.seed = Some(value.parse().map_err(|_| format!("Invalid seed: {}", value))?);
+                }
+                "--rng" => {
+                    let value = next_value()?;
+                    config.rng = RngKind::from_name(value).ok_or_else(|| format!("Unknown RNG: {}", value))?;
+                }
+                "--palette" => config.palette = Some(next_value()?.to_string()),
+                "--palette-file" => config.palette_file = Some(next_value()?.to_string()),
+                "--persistence" => {
+                    let value = next_value()?;
+                    config.persistence = match value {
+                        "off" => Persistence::Off,
+                        "decay" => Persistence::Decay,
+                        "blend" => Persistence::Blend,
+                        _ => return Err(format!("Unknown persistence mode: {}", value)),
+                    };
+                }
+                "--decay-rate" => {
+                    let value = next_value()?;
+                    config.decay_rate = value.parse().ok().filter(|rate| (0.0..=1.0).contains(rate)).ok_or_else(|| format!("Invalid decay rate: {}", value))?;
+                }
+                "--display-wait" => config.display_wait = true,
+                "--integer-scaling" => config.integer_scaling = true,
+                "--fullscreen" => config.fullscreen = true,
+                "--keypad" => config.keypad = true,
+                "--terminal" => config.terminal = true,
+                "--watch" => config.watch = true,
+                "--record" => config.record_path = Some(next_value()?.to_string()),
+                "--replay" => config.replay_path = Some(next_value()?.to_string()),
+                "--profile" => config.profile_path = Some(next_value()?.to_string()),
+                "--coverage" => config.coverage_path = Some(next_value()?.to_string()),
+                "--gdb" => {
+                    let value = next_value()?;
+                    config.gdb_port = Some(value.parse().map_err(|_| format!("Invalid port: {}", value))?);
+                }
+                "--rpc" => {
+                    let value = next_value()?;
+                    config.rpc_port = Some(value.parse().map_err(|_| format!("Invalid port: {}", value))?);
+                }
+                "--script" => config.script_path = Some(next_value()?.to_string()),
+                "--cheat-dir" => config.cheat_dir = next_value()?.to_string(),
+                "--data-dir" => config.data_dir = next_value()?.to_string(),
+                "--gamepad" => config.gamepad = Some(GamepadMapping::parse(next_value()?)?),
+                "--no-gamepad" => config.use_gamepad = false,
+                "--cfg" => config.cfg_path = Some(next_value()?.to_string()),
+                _ => return Err(format!("Unknown option: {}", option)),
+            }
+        }
+
+        if config.record_path.is_some() && config.replay_path.is_some() {
+            return Err("--record and --replay can't be used together".to_string());
+        }
+        Ok(config)
+    }
+}
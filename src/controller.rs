@@ -0,0 +1,689 @@
+use crate::cheat::*;
+use crate::display::*;
+use crate::emulator::*;
+use crate::gamepad::*;
+use crate::gdb::*;
+use crate::movie::*;
+use crate::processor::StackFrame;
+use crate::rpc::*;
+use crate::watcher::*;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How fast frames run compared to the 60 Hz of the real hardware. Only the pacing changes, a
+// frame always does the same work so movies stay in sync at any speed
+#[derive(Clone, Copy, PartialEq)]
+pub enum Speed {
+    Normal,
+    FastForward(u32), // Multiplier
+    Uncapped,
+    SlowMotion(u32), // Divider
+}
+
+impl Speed {
+    fn frame_duration(&self) -> Duration {
+        let normal = 1.0 / 60.0;
+        match self {
+            Speed::Normal => Duration::from_secs_f64(normal),
+            Speed::FastForward(multiplier) => Duration::from_secs_f64(normal / *multiplier as f64),
+            Speed::Uncapped => Duration::ZERO,
+            Speed::SlowMotion(divider) => Duration::from_secs_f64(normal * *divider as f64),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Speed::Normal => "x1".to_string(),
+            Speed::FastForward(multiplier) => format!("x{}", multiplier),
+            Speed::Uncapped => "uncapped".to_string(),
+            Speed::SlowMotion(divider) => format!("x1/{}", divider),
+        }
+    }
+
+    // Normal -> x2 -> x4 -> x8 -> uncapped -> normal
+    pub fn next_fast_forward(&self) -> Speed {
+        match self {
+            Speed::FastForward(multiplier) if *multiplier < 8 => Speed::FastForward(multiplier * 2),
+            Speed::FastForward(_) => Speed::Uncapped,
+            Speed::Uncapped => Speed::Normal,
+            _ => Speed::FastForward(2),
+        }
+    }
+
+    // Normal -> x1/2 -> x1/4 -> normal
+    pub fn next_slow_motion(&self) -> Speed {
+        match self {
+            Speed::SlowMotion(divider) if *divider < 4 => Speed::SlowMotion(divider * 2),
+            Speed::SlowMotion(_) => Speed::Normal,
+            _ => Speed::SlowMotion(2),
+        }
+    }
+}
+
+// Sent from the frontend to the emulator thread
+pub enum Command {
+    Pause,
+    Resume,
+    Step,         // Runs a single instruction, only while paused
+    AdvanceFrame, // Runs a single frame, only while paused
+    Reset,
+    LoadRom(String),
+    SetSpeed(Speed),
+    SetInstructionsPerFrame(u32),
+    SetKeys(u16),
+    InspectCallStack, // Answered with a CallStack event
+    Quit,
+}
+
+#[derive(Clone, Copy)]
+pub struct Status {
+    pub paused: bool,
+    pub halted: bool, // Crashed, waiting for a reset or another ROM
+    pub speed: Speed,
+    pub instructions_per_frame: u32,
+}
+
+// Sent from the emulator thread back to the frontend
+pub enum Event {
+    FrameReady(Display),
+    StatusChanged(Status),
+    BeepOn,
+    BeepOff,
+    Halted(String), // The program crashed, nothing runs until it's reset or another ROM is loaded
+    Break(String),  // The program was paused on something worth inspecting
+    CallStack(Vec<StackFrame>), // Innermost call last
+    PolledKeys(u16), // Keys the program checked with EX9E/EXA1 in the last frame, sent when they change
+    Error(String),
+    Message(String), // Nothing went wrong, but the player might want to know
+}
+
+// Owns the emulator thread. The frontend never touches the emulator directly, it only sends
+// commands and reacts to events, so neither side can block the other
+pub struct Controller {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Controller {
+    // With a watcher the ROM is reloaded whenever the file changes on disk. With a GDB stub the
+    // program waits for a debugger to tell it to run. With an RPC server remote clients can drive
+    // the emulator alongside the frontend. A gamepad adds its keys to the frontend's. Each ROM's
+    // cheats are kept in the cheat directory
+    #[allow(clippy::too_many_arguments)]
+    pub fn start(emu: Emulator, player: Option<MoviePlayer>, recorder: Option<MovieRecorder>, watcher: Option<FileWatcher>, gdb: Option<GdbStub>, rpc: Option<RpcServer>, gamepad: Option<Gamepad>, cheat_dir: String) -> Self {
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (event_sender, event_receiver) = mpsc::channel();
+        let mut runner = Runner {
+            emu,
+            player,
+            recorder,
+            watcher,
+            paused: gdb.is_some(),
+            gdb,
+            rpc,
+            gamepad,
+            cheat_dir,
+            search: None,
+            events: event_sender,
+            keys: 0,
+            remote_keys: 0,
+            speed: Speed::Normal,
+            instructions_per_frame_changed: false,
+            halted: false,
+            beeping: false,
+            polled_keys: 0,
+            reset_pending: false,
+        };
+        let thread = thread::spawn(move || {
+            runner.send_status();
+            runner.load_cheats();
+            runner.run(command_receiver);
+            runner.finish();
+        });
+
+        Controller {
+            commands: command_sender,
+            events: event_receiver,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send(&self, command: Command) {
+        // Only fails once the emulator thread is gone, in which case there's nobody to tell
+        let _ = self.commands.send(command);
+    }
+
+    pub fn events(&self) -> mpsc::TryIter<'_, Event> {
+        self.events.try_iter()
+    }
+
+    // Stops the emulator thread and waits for it to finish
+    pub fn shutdown(&mut self) {
+        self.send(Command::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Runner {
+    emu: Emulator,
+    player: Option<MoviePlayer>,
+    recorder: Option<MovieRecorder>,
+    watcher: Option<FileWatcher>,
+    gdb: Option<GdbStub>,
+    rpc: Option<RpcServer>,
+    gamepad: Option<Gamepad>,
+    cheat_dir: String,
+    search: Option<CheatSearch>,
+    events: Sender<Event>,
+    keys: u16,
+    remote_keys: u16, // Held through the RPC server, on top of the frontend's
+    paused: bool,
+    speed: Speed,
+    instructions_per_frame_changed: bool, // Speed change to write into the movie with the next frame
+    halted: bool,
+    beeping: bool,
+    polled_keys: u16,
+    reset_pending: bool, // Reset to write into the movie with the next frame
+}
+
+impl Runner {
+    fn send(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    fn send_status(&self) {
+        self.send(Event::StatusChanged(Status {
+            paused: self.paused,
+            halted: self.halted,
+            speed: self.speed,
+            instructions_per_frame: self.emu.instructions_per_frame,
+        }));
+    }
+
+    fn run(&mut self, commands: Receiver<Command>) {
+        loop {
+            let frame_start = Instant::now();
+
+            // Drain every pending command. When nothing is running there's no frame to keep
+            // time for, so wait for the frontend to ask for something, waking up now and then to
+            // check the watched ROM
+            loop {
+                // A debugger or remote client wants its answers quickly
+                let timeout = Duration::from_millis(if self.gdb.is_some() || self.rpc.is_some() { 10 } else { 100 });
+                let command = if self.paused || self.halted {
+                    match commands.recv_timeout(timeout) {
+                        Ok(command) => command,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                } else {
+                    match commands.try_recv() {
+                        Ok(command) => command,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                };
+                if !self.handle(command) {
+                    return;
+                }
+            }
+
+            self.poll_gdb();
+            self.poll_rpc();
+            if self.watcher.as_mut().is_some_and(|watcher| watcher.has_changed()) {
+                self.reload_watched();
+            }
+
+            if !self.paused && !self.halted {
+                self.run_frame();
+            }
+            self.send_messages();
+            sleep(self.speed.frame_duration().saturating_sub(frame_start.elapsed()));
+        }
+    }
+
+    // Returns false when the thread should stop
+    fn handle(&mut self, command: Command) -> bool {
+        match command {
+            Command::Pause => {
+                self.paused = true;
+                self.send_status();
+                if let Some(gdb) = self.gdb.as_mut() {
+                    gdb.report_interrupt();
+                }
+            }
+            Command::Resume => {
+                self.paused = false;
+                self.emu.step_over_breakpoint();
+                self.send_status();
+            }
+            Command::Step => {
+                // A movie can only hold whole frames
+                if self.player.is_some() || self.recorder.is_some() {
+                    self.send(Event::Error("Can't single step while a movie is recorded or played".to_string()));
+                } else if self.paused && !self.halted {
+                    self.emu.step_over_breakpoint();
+                    if let Err(e) = self.emu.clock() {
+                        self.halt(e);
+                    }
+                    self.send(Event::FrameReady(self.emu.display.clone()));
+                }
+            }
+            Command::AdvanceFrame => {
+                if self.paused && !self.halted {
+                    self.emu.step_over_breakpoint();
+                    self.run_frame();
+                }
+            }
+            Command::Reset => {
+                if self.player.take().is_some() {
+                    self.send(Event::Message("Movie playback stopped by reset".to_string()));
+                }
+                self.emu.reset();
+                self.halted = false;
+                self.reset_pending = true;
+                self.send_status();
+                self.send(Event::FrameReady(self.emu.display.clone()));
+            }
+            Command::LoadRom(rom_path) => {
+                if let Err(e) = self.load_rom(&rom_path) {
+                    self.send(Event::Error(e));
+                }
+            }
+            Command::SetSpeed(speed) => {
+                self.speed = speed;
+                self.send_status();
+            }
+            Command::SetInstructionsPerFrame(instructions_per_frame) => {
+                self.emu.instructions_per_frame = instructions_per_frame.max(1);
+                self.instructions_per_frame_changed = true;
+                self.send_status();
+            }
+            Command::SetKeys(keys) => self.keys = keys,
+            Command::InspectCallStack => self.send(Event::CallStack(self.emu.call_stack().to_vec())),
+            Command::Quit => return false,
+        }
+        true
+    }
+
+    // A failed reload keeps the old ROM running, the file is likely still being written
+    fn reload_watched(&mut self) {
+        let rom_path = self.watcher.as_ref().unwrap().path.clone();
+        let result = self.load_rom(&rom_path);
+        let Some(watcher) = self.watcher.as_mut() else {
+            return;
+        };
+        if watcher.reloaded(result.is_ok()) {
+            return;
+        }
+        match result {
+            Ok(()) => self.send(Event::Message(format!("{} changed, reloaded", rom_path))),
+            Err(e) => self.send(Event::Error(e)),
+        }
+    }
+
+    fn load_rom(&mut self, rom_path: &str) -> Result<(), String> {
+        let warnings = self.emu.load_rom(rom_path).map_err(|e| format!("Failed to load {}: {}", rom_path, e))?;
+        for warning in warnings {
+            self.send(Event::Message(format!("Warning: {}", warning)));
+        }
+        // Movies only make sense for the ROM they were started with
+        self.stop_movie("a different ROM was loaded");
+        if let Some(watcher) = self.watcher.as_mut() {
+            if watcher.path != rom_path {
+                *watcher = FileWatcher::new(rom_path);
+            }
+        }
+        self.search = None;
+        self.load_cheats();
+        self.halted = false;
+        self.send_status();
+        self.send(Event::FrameReady(self.emu.display.clone()));
+        Ok(())
+    }
+
+    fn cheat_path(&self) -> PathBuf {
+        cheat_path(&self.cheat_dir, self.emu.rom_hash())
+    }
+
+    // Cheats aren't saved in movies, so they stay off while one is recorded or played, like
+    // the RPL flags
+    fn load_cheats(&mut self) {
+        if self.player.is_some() || self.recorder.is_some() {
+            self.emu.set_cheats(Vec::new());
+            return;
+        }
+        let path = self.cheat_path();
+        match load_cheats(&path) {
+            Ok(cheats) => {
+                if !cheats.is_empty() {
+                    self.send(Event::Message(format!("Loaded {} cheats from {}", cheats.len(), path.display())));
+                }
+                self.emu.set_cheats(cheats);
+            }
+            Err(e) => {
+                self.emu.set_cheats(Vec::new());
+                self.send(Event::Error(format!("Failed to load cheats: {}", e)));
+            }
+        }
+    }
+
+    fn stop_movie(&mut self, reason: &str) {
+        if self.player.take().is_some() || self.recorder.take().is_some() {
+            self.send(Event::Message(format!("Stopped the movie, {}", reason)));
+        }
+    }
+
+    // Passes on what the emulator and gamepad have to say. Only the frontend writes to the
+    // screen, the terminal one would get drawn over
+    fn send_messages(&mut self) {
+        let mut messages = self.emu.take_messages();
+        messages.extend(self.gamepad.as_ref().map(Gamepad::take_messages).unwrap_or_default());
+        for message in messages {
+            self.send(Event::Message(message));
+        }
+    }
+
+    // Called once the thread is told to stop. The frontend is gone by then, so this prints
+    fn finish(&mut self) {
+        if let Some(coverage) = &self.emu.mem.coverage {
+            match coverage.save(&self.emu.mem, self.emu.memory_map.load_address, self.emu.rom().len()) {
+                Ok(path) => println!("Wrote coverage to {}", path),
+                Err(e) => eprintln!("Failed to write coverage: {}", e),
+            }
+        }
+        if let Some(profiler) = &self.emu.profiler {
+            match profiler.save() {
+                Ok(path) => println!("Wrote profile to {}", path),
+                Err(e) => eprintln!("Failed to write profile: {}", e),
+            }
+        }
+    }
+
+    fn poll_gdb(&mut self) {
+        let movie = self.player.is_some() || self.recorder.is_some();
+        let Some(gdb) = self.gdb.as_mut() else {
+            return;
+        };
+        // Changing the machine would make the movie play out differently
+        gdb.read_only = movie;
+        for action in gdb.poll(&mut self.emu) {
+            match action {
+                GdbAction::Attached => {
+                    self.send(Event::Message("Debugger attached".to_string()));
+                    self.paused = true;
+                    self.send_status();
+                }
+                GdbAction::Continue => {
+                    if self.halted {
+                        self.gdb.as_mut().unwrap().report_halted();
+                    } else {
+                        self.handle(Command::Resume);
+                    }
+                }
+                GdbAction::Step => {
+                    // Movies only hold whole frames, so stepping goes nowhere while one is on
+                    if self.halted {
+                        self.gdb.as_mut().unwrap().report_halted();
+                    } else if self.player.is_some() || self.recorder.is_some() {
+                        self.gdb.as_mut().unwrap().report_stop(None);
+                    } else {
+                        self.emu.step_over_breakpoint();
+                        match self.emu.clock() {
+                            Ok(()) => self.gdb.as_mut().unwrap().report_stop(None),
+                            Err(e) => self.halt(e),
+                        }
+                        self.send(Event::FrameReady(self.emu.display.clone()));
+                    }
+                }
+                GdbAction::Interrupt => {
+                    self.handle(Command::Pause);
+                }
+                GdbAction::Detached => {
+                    self.send(Event::Message("Debugger detached".to_string()));
+                    self.emu.breakpoints.clear();
+                    self.emu.mem.watchpoints.clear();
+                    self.handle(Command::Resume);
+                }
+            }
+        }
+    }
+
+    fn poll_rpc(&mut self) {
+        let Some(rpc) = self.rpc.as_mut() else {
+            return;
+        };
+        for request in rpc.poll() {
+            let result = self.handle_rpc(&request);
+            self.rpc.as_mut().unwrap().respond(&request, result);
+        }
+    }
+
+    fn handle_rpc(&mut self, request: &RpcRequest) -> Result<Value, RpcError> {
+        match request.method.as_str() {
+            "load_rom" => {
+                self.load_rom(request.string("path")?).map_err(RpcError::failed)?;
+            }
+            "reset" => {
+                self.handle(Command::Reset);
+            }
+            "pause" => {
+                self.handle(Command::Pause);
+            }
+            "resume" => {
+                self.handle(Command::Resume);
+            }
+            "step" => {
+                let count = request.number("count", Some(1))?;
+                if self.player.is_some() || self.recorder.is_some() {
+                    return Err(RpcError::failed("Can't single step while a movie is recorded or played".to_string()));
+                }
+                for _ in 0..count {
+                    if self.halted {
+                        break;
+                    }
+                    self.emu.step_over_breakpoint();
+                    if let Err(e) = self.emu.clock() {
+                        self.halt(e);
+                        break;
+                    }
+                }
+                self.send(Event::FrameReady(self.emu.display.clone()));
+            }
+            "run_frames" => {
+                let count = request.number("count", Some(1))?;
+                for _ in 0..count {
+                    if self.halted {
+                        break;
+                    }
+                    self.emu.step_over_breakpoint();
+                    self.run_frame();
+                }
+            }
+            "press_key" | "release_key" => {
+                let key = request.number("key", None)?;
+                if key > 0xF {
+                    return Err(RpcError::invalid_params("key must be 0-15"));
+                }
+                if request.method == "press_key" {
+                    self.remote_keys |= 1 << key;
+                } else {
+                    self.remote_keys &= !(1 << key);
+                }
+            }
+            "read_memory" => {
+                let address = request.number("address", None)? as usize;
+                let length = request.number("length", Some(1))? as usize;
+                if address.saturating_add(length) > self.emu.mem.size() {
+                    return Err(RpcError::invalid_params("Range is outside of memory"));
+                }
+                let bytes: Vec<u8> = (address..address + length).map(|address| self.emu.mem.peek(address)).collect();
+                return Ok(json!(bytes));
+            }
+            "write_memory" => {
+                if self.player.is_some() || self.recorder.is_some() {
+                    return Err(RpcError::failed("Can't write memory while a movie is recorded or played".to_string()));
+                }
+                let address = request.number("address", None)? as usize;
+                let data = request.bytes("data")?;
+                if address.saturating_add(data.len()) > self.emu.mem.size() {
+                    return Err(RpcError::invalid_params("Range is outside of memory"));
+                }
+                for (offset, byte) in data.iter().enumerate() {
+                    self.emu.mem.poke(address + offset, *byte);
+                }
+            }
+            "get_registers" => {
+                let proc = &self.emu.proc;
+                let stack: Vec<u16> = proc.stack.iter().map(|frame| frame.return_address).collect();
+                return Ok(json!({
+                    "v": proc.registers,
+                    "i": proc.address_register,
+                    "pc": proc.program_counter,
+                    "sp": proc.stack.len(),
+                    "dt": proc.delay_timer.value,
+                    "st": proc.sound_timer.value,
+                    "stack": stack,
+                }));
+            }
+            "get_framebuffer" => {
+                let display = &self.emu.display;
+                return Ok(json!({ "width": display.width, "height": display.height, "pixels": display.pixels }));
+            }
+            "save_state" => {
+                let path = request.string("path")?;
+                fs::write(path, self.emu.save_state()).map_err(|e| RpcError::failed(format!("Failed to write {}: {}", path, e)))?;
+            }
+            "load_state" => {
+                let path = request.string("path")?;
+                let text = fs::read_to_string(path).map_err(|e| RpcError::failed(format!("Failed to read {}: {}", path, e)))?;
+                self.emu.load_state(&text).map_err(|e| RpcError::failed(format!("Failed to load state from {}: {}", path, e)))?;
+                // The movie would no longer match what's running
+                self.stop_movie("a state was loaded");
+                self.halted = false;
+                self.send_status();
+                self.send(Event::FrameReady(self.emu.display.clone()));
+            }
+            "cheat_search_start" => {
+                let search = CheatSearch::new(&self.emu.mem);
+                let count = search.candidates.len();
+                self.search = Some(search);
+                return Ok(json!({ "count": count }));
+            }
+            "cheat_search" => {
+                let value = match request.params["value"] {
+                    Value::Null => None,
+                    _ => Some(request.number("value", None)?).filter(|value| *value <= 0xFF).map(|value| value as u8),
+                };
+                let comparison = Comparison::from_name(request.string("comparison")?, value).ok_or_else(|| RpcError::invalid_params("comparison must be equal (with a value), changed, unchanged, increased or decreased"))?;
+                let search = self.search.get_or_insert_with(|| CheatSearch::new(&self.emu.mem));
+                search.filter(&self.emu.mem, comparison);
+                // Long lists aren't worth reading, only the count matters until it gets short
+                let matches: Vec<Value> = search.candidates.iter().take(100).map(|address| json!({ "address": address, "value": self.emu.mem.peek(*address) })).collect();
+                return Ok(json!({ "count": search.candidates.len(), "matches": matches }));
+            }
+            "add_cheat" => {
+                if self.player.is_some() || self.recorder.is_some() {
+                    return Err(RpcError::failed("Cheats are off while a movie is recorded or played".to_string()));
+                }
+                let address = request.number("address", None)? as usize;
+                let value = request.number("value", None)?;
+                if address >= self.emu.mem.size() || value > 0xFF {
+                    return Err(RpcError::invalid_params("Address or value out of range"));
+                }
+                let freeze = request.boolean("freeze", true)?;
+                let description = request.params["description"].as_str().unwrap_or_default().to_string();
+                self.emu.add_cheat(Cheat { address, value: value as u8, freeze, description });
+            }
+            "remove_cheat" => {
+                let address = request.number("address", None)? as usize;
+                if !self.emu.remove_cheat(address) {
+                    return Err(RpcError::failed(format!("No cheat at {:03X}", address)));
+                }
+            }
+            "list_cheats" => {
+                let cheats: Vec<Value> = self.emu.cheats().iter().map(|cheat| json!({ "address": cheat.address, "value": cheat.value, "freeze": cheat.freeze, "description": cheat.description })).collect();
+                return Ok(json!(cheats));
+            }
+            "save_cheats" => {
+                let path = self.cheat_path();
+                save_cheats(&path, self.emu.cheats()).map_err(|e| RpcError::failed(format!("Failed to write {}: {}", path.display(), e)))?;
+                return Ok(json!(path.display().to_string()));
+            }
+            method => return Err(RpcError::method_not_found(method)),
+        }
+        Ok(Value::Null)
+    }
+
+    fn halt(&mut self, error: EmulatorError) {
+        if let Some(gdb) = self.gdb.as_mut() {
+            gdb.report_stop(Some(&error));
+        }
+        if error.is_debug_break() {
+            self.paused = true;
+            self.send(Event::Break(error.to_string()));
+            self.send_status();
+        } else {
+            self.halted = true;
+            self.send(Event::Halted(error.to_string()));
+            self.send_status();
+        }
+    }
+
+    fn run_frame(&mut self) {
+        let mut frame = MovieFrame {
+            keys: self.keys | self.remote_keys | self.gamepad.as_ref().map_or(0, Gamepad::keys),
+            reset: self.reset_pending,
+            instructions_per_frame: Some(self.emu.instructions_per_frame).filter(|_| self.instructions_per_frame_changed),
+        };
+        self.reset_pending = false;
+        self.instructions_per_frame_changed = false;
+
+        match self.player.as_mut().map(|player| player.next_frame()) {
+            Some(Some(movie_frame)) => {
+                if movie_frame.reset {
+                    self.emu.reset();
+                }
+                if let Some(instructions_per_frame) = movie_frame.instructions_per_frame {
+                    self.emu.instructions_per_frame = instructions_per_frame;
+                    self.send_status();
+                }
+                frame = movie_frame;
+            }
+            Some(None) => {
+                self.send(Event::Message("Movie finished, switching to keyboard input".to_string()));
+                self.player = None;
+            }
+            None => {}
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record_frame(frame) {
+                self.recorder = None;
+                self.send(Event::Error(format!("Stopped recording, failed to write movie frame: {}", e)));
+            }
+        }
+
+        self.emu.keypad = frame.keys;
+        if let Err(e) = self.emu.run_frame() {
+            self.halt(e);
+        }
+        self.send(Event::FrameReady(self.emu.display.clone()));
+
+        let beeping = self.emu.is_sound_playing();
+        if beeping != self.beeping {
+            self.send(if beeping { Event::BeepOn } else { Event::BeepOff });
+            self.beeping = beeping;
+        }
+        if self.emu.polled_keys() != self.polled_keys {
+            self.polled_keys = self.emu.polled_keys();
+            self.send(Event::PolledKeys(self.polled_keys));
+        }
+    }
+}
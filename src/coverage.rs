@@ -0,0 +1,108 @@
+use crate::disassembler::*;
+use crate::memory::*;
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+
+const FETCHED: u8 = 1;
+const READ: u8 = 2;
+const WRITTEN: u8 = 4;
+
+// Remembers how each address was used: fetched as an instruction, read as data or written.
+// Reads go through &Memory, hence the cells
+pub struct Coverage {
+    output_path: String,
+    accesses: Vec<Cell<u8>>,
+}
+
+impl Coverage {
+    pub fn new(output_path: &str, memory_size: usize) -> Self {
+        Coverage {
+            output_path: output_path.to_string(),
+            accesses: vec![Cell::new(0); memory_size],
+        }
+    }
+
+    // Throws away everything recorded so far, for when a different program is loaded
+    pub fn clear(&mut self) {
+        *self = Coverage::new(&self.output_path, self.accesses.len());
+    }
+
+    pub fn record(&self, address: usize, kind: AccessKind) {
+        let flag = match kind {
+            AccessKind::Fetch => FETCHED,
+            AccessKind::Read => READ,
+            AccessKind::Write => WRITTEN,
+        };
+        if let Some(access) = self.accesses.get(address) {
+            access.set(access.get() | flag);
+        }
+    }
+
+    fn has(&self, address: usize, flag: u8) -> bool {
+        self.accesses.get(address).is_some_and(|access| access.get() & flag != 0)
+    }
+
+    pub fn is_code(&self, address: usize) -> bool {
+        self.has(address, FETCHED)
+    }
+
+    fn flags(&self, address: usize) -> String {
+        let flag = |flag, letter| if self.has(address, flag) { letter } else { '-' };
+        [flag(FETCHED, 'x'), flag(READ, 'r'), flag(WRITTEN, 'w')].iter().collect()
+    }
+
+    // Lists the ROM with every line marked by how it was used. Executed addresses are shown as
+    // instructions, bytes only read as data with their bits drawn out since they're usually
+    // sprites, and anything never touched as instructions for what they'd be worth. Also returns
+    // the listing line of every instruction with whether it ran, for the lcov summary
+    fn listing(&self, mem: &Memory, start: usize, length: usize) -> (String, Vec<(usize, bool)>) {
+        let end = start + length;
+        let mut lines = Vec::new();
+        let mut instruction_lines = Vec::new();
+        let mut address = start;
+        while address < end {
+            let byte = mem.peek(address);
+            let untouched = |address| !self.has(address, FETCHED | READ);
+            if self.is_code(address) || (untouched(address) && address + 1 < end && untouched(address + 1)) {
+                let opcode = ((byte as u16) << 8) | mem.peek(address + 1) as u16;
+                instruction_lines.push((lines.len() + 1, self.is_code(address)));
+                lines.push(format!("{:03X}  {:04X}  {}  {}", address, opcode, self.flags(address), disassemble(opcode)));
+                address += 2;
+            } else {
+                let bits: String = (0..8).rev().map(|bit| if (byte >> bit) & 1 == 1 { '#' } else { '.' }).collect();
+                lines.push(format!("{:03X}  {:02X}    {}  DB {:02X}  ; {}", address, byte, self.flags(address), byte, bits));
+                address += 1;
+            }
+        }
+        (lines.join("\n"), instruction_lines)
+    }
+
+    // Writes the annotated listing of the ROM to the output file, and an lcov tracefile next to
+    // it (same name plus .info) that points at the listing so lcov tools can show it
+    pub fn save(&self, mem: &Memory, rom_start: usize, rom_length: usize) -> io::Result<&str> {
+        let (listing, instruction_lines) = self.listing(mem, rom_start, rom_length);
+        let executed = instruction_lines.iter().filter(|(_, executed)| *executed).count();
+        let rom = rom_start..rom_start + rom_length;
+        let count = |flag| rom.clone().filter(|address| self.has(*address, flag)).count();
+        let written_outside = (0..self.accesses.len()).filter(|address| self.has(*address, WRITTEN) && !rom.contains(address)).count();
+
+        let mut report = String::new();
+        let _ = writeln!(report, "; {} of {} instructions executed", executed, instruction_lines.len());
+        let _ = writeln!(report, "; {} ROM bytes read as data, {} ROM bytes written, {} bytes written outside of the ROM", count(READ), count(WRITTEN), written_outside);
+        let _ = writeln!(report, "; x = executed, r = read, w = written");
+        let header_lines = report.lines().count();
+        report.push_str(&listing);
+        report.push('\n');
+        fs::write(&self.output_path, report)?;
+
+        let mut tracefile = format!("TN:\nSF:{}\n", self.output_path);
+        for (line, executed) in &instruction_lines {
+            let _ = writeln!(tracefile, "DA:{},{}", line + header_lines, *executed as u8);
+        }
+        let _ = writeln!(tracefile, "LF:{}\nLH:{}\nend_of_record", instruction_lines.len(), executed);
+        fs::write(format!("{}.info", self.output_path), tracefile)?;
+        Ok(&self.output_path)
+    }
+}
@@ -0,0 +1,104 @@
+// Turns an opcode into the usual CHIP-8 assembly mnemonics. Opcodes that aren't instructions
+// on any platform come out as a raw word
+pub fn disassemble(opcode: u16) -> String {
+    let x = (opcode >> 8) & 0xF;
+    let y = (opcode >> 4) & 0xF;
+    let n = opcode & 0xF;
+    let nn = opcode & 0xFF;
+    let nnn = opcode & 0xFFF;
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            0x00FB => "SCR".to_string(),
+            0x00FC => "SCL".to_string(),
+            0x00FD => "EXIT".to_string(),
+            0x00FE => "LOW".to_string(),
+            0x00FF => "HIGH".to_string(),
+            _ if opcode & 0xFFF0 == 0x00C0 => format!("SCD {}", n),
+            _ => format!("SYS {:03X}", nnn),
+        },
+        0x1000 => format!("JP {:03X}", nnn),
+        0x2000 => format!("CALL {:03X}", nnn),
+        0x3000 => format!("SE V{:X}, {:02X}", x, nn),
+        0x4000 => format!("SNE V{:X}, {:02X}", x, nn),
+        0x5000 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, {:02X}", x, nn),
+        0x7000 => format!("ADD V{:X}, {:02X}", x, nn),
+        0x8000 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("DW {:04X}", opcode),
+        },
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, {:03X}", nnn),
+        0xB000 => format!("JP V0, {:03X}", nnn),
+        0xC000 => format!("RND V{:X}, {:02X}", x, nn),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("DW {:04X}", opcode),
+        },
+        0xF000 => match nn {
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            0x85 => format!("LD V{:X}, R", x),
+            _ => format!("DW {:04X}", opcode),
+        },
+        _ => format!("DW {:04X}", opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        let cases = [
+            (0x00E0, "CLS"),
+            (0x00C4, "SCD 4"),
+            (0x0123, "SYS 123"),
+            (0x1ABC, "JP ABC"),
+            (0x2300, "CALL 300"),
+            (0x3A07, "SE VA, 07"),
+            (0x5120, "SE V1, V2"),
+            (0x8AB6, "SHR VA, VB"),
+            (0x8ABE, "SHL VA, VB"),
+            (0xB400, "JP V0, 400"),
+            (0xD12F, "DRW V1, V2, 15"),
+            (0xE59E, "SKP V5"),
+            (0xF30A, "LD V3, K"),
+            (0xFF55, "LD [I], VF"),
+            (0xF285, "LD V2, R"),
+        ];
+        for (opcode, text) in cases {
+            assert_eq!(disassemble(opcode), text, "{:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_are_data() {
+        for opcode in [0x5121, 0x8128, 0x9121, 0xE5A2, 0xF099] {
+            assert_eq!(disassemble(opcode), format!("DW {:04X}", opcode));
+        }
+    }
+}
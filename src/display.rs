@@ -0,0 +1,65 @@
+// The framebuffer the CHIP-8 draws into. It belongs to the emulator, frontends only ever get a
+// copy of it once a frame has been completed
+#[derive(Clone)]
+pub struct Display {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Display {
+            width: 64,
+            height: 32,
+            pixels: vec![0u8; 64 * 32],
+        }
+    }
+
+    // Resolutions programs can run in, anything else didn't come from a running program. The
+    // frontends draw any size, for SUPER-CHIP's 128x64 once its instructions are in
+    pub fn is_supported(width: usize, height: usize) -> bool {
+        (width, height) == (64, 32)
+    }
+
+    // Also clears the screen
+    pub fn set_resolution(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![0; width * height];
+    }
+
+    pub fn clear_pixels(&mut self) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel = 0;
+        }
+    }
+
+    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: Vec<u8>) -> bool {
+        let mut written_pixel = false;
+        for (row, block) in sprite.iter().enumerate() {
+            let current_y = y as usize + row;
+            if current_y >= self.height {
+                break;
+            }
+            for i in 0..8 {
+                if (i + x as usize) >= self.width {
+                    break;
+                }
+                let current_pixel: usize = current_y * self.width + i + x as usize;
+                if self.pixels[current_pixel] != ((block << i) & 128) >> 7 && !written_pixel {
+                    written_pixel = true;
+                }
+                if (((block << i) & 128) >> 7) == 1 {
+                    if self.pixels[current_pixel] == 0 {
+                        self.pixels[current_pixel] = 1;
+                    } else {
+                        self.pixels[current_pixel] = 0;
+                    }
+                }
+
+            }
+        }
+        written_pixel
+    }
+}
@@ -1,59 +1,509 @@
+use crate::cheat::*;
+use crate::hex;
 use crate::processor::*;
 use crate::memory::*;
-use crate::renderer::*;
-use crate::keyboard::*;
-use std::mem;
-use std::process;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread;
-use std::thread::sleep;
-use std::time::Duration;
-use std::usize;
-use rand::random;
+use crate::patch::*;
+use crate::display::*;
+use crate::platform::*;
+use crate::profiler::*;
+use crate::rng::*;
+use crate::rom::*;
+use crate::script::*;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub enum EmulatorError {
+    MemoryFault(MemoryFault),
+    StackOverflow(usize), // Address of the 2NNN that overflowed
+    StackUnderflow(usize),
+    Breakpoint(usize),
+    Watchpoint { address: usize, kind: AccessKind },
+    Script(String),
+}
+
+impl EmulatorError {
+    // Errors that should pause the program for inspection rather than stop it
+    pub fn is_debug_break(&self) -> bool {
+        match self {
+            EmulatorError::MemoryFault(fault) => fault.debug_break,
+            EmulatorError::Breakpoint(_) | EmulatorError::Watchpoint { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<MemoryFault> for EmulatorError {
+    fn from(fault: MemoryFault) -> Self {
+        EmulatorError::MemoryFault(fault)
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::MemoryFault(fault) => write!(f, "{}", fault),
+            EmulatorError::StackOverflow(address) => write!(f, "Stack overflow at {:03X}", address),
+            EmulatorError::StackUnderflow(address) => write!(f, "Stack underflow at {:03X}", address),
+            EmulatorError::Breakpoint(address) => write!(f, "Breakpoint at {:03X}", address),
+            EmulatorError::Watchpoint { address, kind } => {
+                let access = if *kind == AccessKind::Write { "written" } else { "read" };
+                write!(f, "Watched address {:03X} was {}", address, access)
+            }
+            EmulatorError::Script(message) => write!(f, "Script error: {}", message),
+        }
+    }
+}
 
 pub struct Emulator {
-    proc: Processor,
+    pub platform: Platform,
+    pub memory_map: MemoryMap,
+    pub memory_policy: MemoryPolicy,
+    pub stack_depth: usize,
+    pub vip_stack: bool, // Mirror the call stack into memory at 0xEA0-0xECF like the VIP did
+    pub proc: Processor,
     pub mem: Memory,
-    renderer_mutex: Arc<Mutex<Renderer>>,
-    keyboard_mutex: Arc<Mutex<Keyboard>>,
-    thread_lock_mutex: Arc<Mutex<bool>>,
+    pub keypad: u16, // Keys held during the current frame, one bit per hex key
+    polled_keys: u16, // Keys checked by EX9E/EXA1 during the last frame
+    pub instructions_per_frame: u32,
+    rng: Box<dyn RandomSource>,
+    pub display: Display,
+    pub display_wait: bool, // VIP quirk: DXYN waits for the next 60 Hz interrupt
+    frame_ended: bool,
+    pub profiler: Option<Profiler>,
+    pub breakpoints: HashSet<usize>,
+    script: Option<Script>,
+    cheats: Vec<Cheat>,
+    skip_breakpoint: bool, // Lets the program resume from the breakpoint it stopped on
+    pub flags_dir: Option<String>, // Where RPL flags are kept between runs, one file per ROM
+    rpl_flags: [u8; 16], // SUPER-CHIP's HP48 user flags, 8 of them or 16 on XO-CHIP
+    pub patches: Vec<Patch>, // For the ROM file loaded next, dropped when another file is loaded
+    rom_path: String,
+    rom_hash: String, // Of the unpatched ROM, names the files kept for it
+    rom: Vec<u8>,  // Kept around so the program can be reset
+    font: Vec<u8>,
+    messages: Vec<String>, // For the player, since the last take_messages
 }
 
 impl Emulator {
-    pub fn new(renderer_mutex_copy: Arc<Mutex<Renderer>>, keyboard_mutex_copy: Arc<Mutex<Keyboard>>, thread_lock_copy: Arc<Mutex<bool>>) -> Self {
+    pub fn new() -> Self {
         Emulator {
-            proc: Processor::default(),
-            mem: Memory::new(),
-            renderer_mutex: renderer_mutex_copy,
-            keyboard_mutex: keyboard_mutex_copy,
-            thread_lock_mutex: thread_lock_copy,
+            platform: Platform::Chip8,
+            memory_map: Platform::Chip8.memory_map(),
+            memory_policy: MemoryPolicy::Fault,
+            stack_depth: Platform::Chip8.stack_depth(),
+            vip_stack: false,
+            proc: Processor::new(Platform::Chip8.stack_depth()),
+            mem: Memory::new(Platform::Chip8.memory_map().size, MemoryPolicy::Fault),
+            keypad: 0,
+            polled_keys: 0,
+            instructions_per_frame: 10,
+            rng: Box::new(SeededRandom::new(0)), // Fixed seed unless told otherwise, so runs are repeatable
+            display: Display::new(),
+            display_wait: false,
+            frame_ended: false,
+            profiler: None,
+            breakpoints: HashSet::new(),
+            script: None,
+            cheats: Vec::new(),
+            skip_breakpoint: false,
+            flags_dir: None,
+            rpl_flags: [0; 16],
+            patches: Vec::new(),
+            rom_path: String::new(),
+            rom_hash: String::new(),
+            rom: Vec::new(),
+            font: Vec::new(),
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn load_font(&mut self, file_path: &str) -> io::Result<()> {
+        self.font = fs::read(file_path)?;
+        self.mem.load_at_location(&self.font, 0x0);
+        Ok(())
+    }
+
+    // Memory is sized for the platform, so this takes effect on the next reset or ROM load.
+    // Replaces the memory map and stack depth with the platform's default ones
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        self.memory_map = platform.memory_map();
+        self.stack_depth = platform.stack_depth();
+    }
+
+    // Returns warnings about the ROM, which is loaded anyway
+    pub fn load_rom(&mut self, file_path: &str) -> Result<Vec<String>, RomError> {
+        if !self.rom_path.is_empty() && self.rom_path != file_path {
+            self.patches.clear();
+        }
+        let rom = load_rom(file_path, self.platform, self.memory_map, &self.patches)?;
+        self.rom_path = file_path.to_string();
+        self.rom = rom.data;
+        self.rom_hash = rom.hash;
+        self.load_rpl_flags();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.clear();
+        }
+        if let Some(coverage) = self.mem.coverage.as_mut() {
+            coverage.clear();
+        }
+        self.reset();
+        Ok(rom.warnings)
+    }
+
+    // Nothing is printed from here, the frontend decides where messages go
+    pub fn take_messages(&mut self) -> Vec<String> {
+        let mut messages = std::mem::take(&mut self.messages);
+        messages.extend(self.script.as_ref().map(Script::take_messages).unwrap_or_default());
+        messages
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn rom_hash(&self) -> &str {
+        &self.rom_hash
+    }
+
+    fn rpl_flags_path(&self) -> Option<PathBuf> {
+        self.flags_dir.as_ref().map(|flags_dir| Path::new(flags_dir).join(format!("{}.rpl", self.rom_hash)))
+    }
+
+    fn rpl_flag_count(&self) -> usize {
+        if self.platform == Platform::XoChip { 16 } else { 8 }
+    }
+
+    // The flags outlive the program, so a ROM without a file starts with them cleared
+    fn load_rpl_flags(&mut self) {
+        self.rpl_flags = [0; 16];
+        let Some(path) = self.rpl_flags_path() else {
+            return;
+        };
+        match fs::read(&path) {
+            Ok(flags) => {
+                let count = flags.len().min(16);
+                self.rpl_flags[..count].copy_from_slice(&flags[..count]);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => self.messages.push(format!("Failed to read RPL flags from {}: {}", path.display(), e)),
+        }
+    }
+
+    fn save_rpl_flags(&mut self) {
+        let Some(path) = self.rpl_flags_path() else {
+            return;
+        };
+        let result = fs::create_dir_all(path.parent().unwrap()).and_then(|_| fs::write(&path, &self.rpl_flags[..self.rpl_flag_count()]));
+        if let Err(e) = result {
+            self.messages.push(format!("Failed to write RPL flags to {}: {}", path.display(), e));
+        }
+    }
+
+    // Puts the machine back in its power on state with the font and ROM freshly loaded. The RNG
+    // keeps going, so a reset doesn't replay the same random numbers
+    pub fn reset(&mut self) {
+        self.proc = Processor::new(self.stack_depth);
+        self.replace_memory(Memory::new(self.memory_map.size, self.memory_policy));
+        self.mem.load_at_location(&self.font, 0x0);
+        self.mem.load_at_location(&self.rom, self.memory_map.load_address);
+        for cheat in &self.cheats {
+            cheat.apply(&mut self.mem);
+        }
+        self.display = Display::new();
+        self.keypad = 0;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.reset();
         }
+        self.prepare();
     }
     
+    // Innermost call last
+    pub fn call_stack(&self) -> &[StackFrame] {
+        &self.proc.stack
+    }
+
+    // Where the VIP kept the return address of the call at the given depth, from 1 up to
+    // VIP_STACK_SLOTS which the stack depth is kept within
+    fn vip_stack_slot(depth: usize) -> usize {
+        VIP_STACK_TOP - 2 * depth
+    }
+
+    // Coverage and watchpoints are about the program, not the memory, so they stay
+    fn replace_memory(&mut self, mut mem: Memory) {
+        mem.coverage = self.mem.coverage.take();
+        mem.watchpoints = std::mem::take(&mut self.mem.watchpoints);
+        mem.record_writes = self.mem.record_writes;
+        self.mem = mem;
+    }
+
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    // Replaces the cheats, applying all of them right away
+    pub fn set_cheats(&mut self, cheats: Vec<Cheat>) {
+        self.cheats = cheats;
+        for cheat in &self.cheats {
+            cheat.apply(&mut self.mem);
+        }
+    }
+
+    // Takes the place of any cheat on the same address
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        cheat.apply(&mut self.mem);
+        self.cheats.retain(|other| other.address != cheat.address);
+        self.cheats.push(cheat);
+        self.cheats.sort_by_key(|cheat| cheat.address);
+    }
+
+    pub fn remove_cheat(&mut self, address: usize) -> bool {
+        let count = self.cheats.len();
+        self.cheats.retain(|cheat| cheat.address != address);
+        self.cheats.len() != count
+    }
+
+    pub fn set_script(&mut self, script: Script) {
+        self.script = Some(script);
+        self.mem.record_writes = true;
+    }
+
+    // What a script callback gets to look at and change
+    fn hardware(&mut self) -> (Option<&mut Script>, Hardware<'_>) {
+        let hardware = Hardware { proc: &mut self.proc, mem: &mut self.mem, display: &mut self.display, keypad: &mut self.keypad };
+        (self.script.as_mut(), hardware)
+    }
+
+    // Everything needed to pick the program up again exactly where it was, RNG included. The
+    // ROM is saved too so resets after loading still work
+    pub fn save_state(&self) -> String {
+        let stack: Vec<Value> = self.proc.stack.iter().map(|frame| json!([frame.return_address, frame.call_site])).collect();
+        let memory: Vec<u8> = (0..self.mem.size()).map(|address| self.mem.peek(address)).collect();
+        let state = json!({
+            "version": 1,
+            "platform": self.platform.id(),
+            "memory_size": self.memory_map.size,
+            "load_address": self.memory_map.load_address,
+            "entry_point": self.memory_map.entry_point,
+            "stack_depth": self.stack_depth,
+            "vip_stack": self.vip_stack,
+            "display_wait": self.display_wait,
+            "instructions_per_frame": self.instructions_per_frame,
+            "registers": self.proc.registers,
+            "index": self.proc.address_register,
+            "pc": self.proc.program_counter,
+            "stack": stack,
+            "delay_timer": self.proc.delay_timer.value,
+            "sound_timer": self.proc.sound_timer.value,
+            "keypad": self.keypad,
+            "rng": { "kind": self.rng.kind().name(), "state": self.rng.state() },
+            "display": { "width": self.display.width, "height": self.display.height, "pixels": hex::encode(&self.display.pixels) },
+            "memory": hex::encode(&memory),
+            "rom": hex::encode(&self.rom),
+            "rom_hash": self.rom_hash,
+        });
+        state.to_string()
+    }
+
+    // Leaves the emulator untouched when the state can't be read
+    pub fn load_state(&mut self, text: &str) -> Result<(), String> {
+        let state: Value = serde_json::from_str(text).map_err(|e| format!("Invalid save state: {}", e))?;
+        let number = |value: &Value| value.as_u64().ok_or_else(|| format!("Invalid save state: expected a number, got {}", value));
+        let bytes = |value: &Value| value.as_str().and_then(hex::decode).ok_or_else(|| "Invalid save state: expected hex data".to_string());
+        if state["version"] != 1 {
+            return Err(format!("Unsupported save state version {}", state["version"]));
+        }
+
+        let platform = state["platform"].as_str().and_then(Platform::from_name).ok_or("Invalid save state: unknown platform")?;
+        let memory_map = MemoryMap {
+            size: number(&state["memory_size"])? as usize,
+            load_address: number(&state["load_address"])? as usize,
+            entry_point: number(&state["entry_point"])? as usize,
+        };
+        // Resets load the font and ROM back in, both have to fit
+        if memory_map.size != platform.memory_map().size {
+            return Err(format!("Invalid save state: {} has {} bytes of memory, not {}", platform.name(), platform.memory_map().size, memory_map.size));
+        }
+        if memory_map.entry_point >= memory_map.size {
+            return Err(format!("Invalid save state: entry point {:X} is outside of memory", memory_map.entry_point));
+        }
+        let rom = bytes(&state["rom"])?;
+        if self.font.len() > memory_map.size || memory_map.load_address.checked_add(rom.len()).is_none_or(|end| end > memory_map.size) {
+            return Err("Invalid save state: the ROM doesn't fit in memory".to_string());
+        }
+        let memory = bytes(&state["memory"])?;
+        if memory.len() != memory_map.size {
+            return Err("Invalid save state: memory doesn't match its size".to_string());
+        }
+        let registers = state["registers"].as_array().filter(|registers| registers.len() == 16).ok_or("Invalid save state: expected 16 registers")?;
+        let stack_depth = number(&state["stack_depth"])? as usize;
+        let vip_stack = state["vip_stack"].as_bool().unwrap_or(false);
+        if stack_depth == 0 || (vip_stack && stack_depth > VIP_STACK_SLOTS) {
+            return Err(format!("Invalid save state: stack depth {} doesn't fit", stack_depth));
+        }
+        let mut proc = Processor::new(stack_depth);
+        for (register, value) in proc.registers.iter_mut().zip(registers) {
+            *register = number(value)? as u8;
+        }
+        proc.address_register = number(&state["index"])? as u16;
+        proc.program_counter = number(&state["pc"])? as usize;
+        if proc.program_counter >= memory_map.size {
+            return Err(format!("Invalid save state: PC {:X} is outside of memory", proc.program_counter));
+        }
+        let stack = state["stack"].as_array().ok_or("Invalid save state: missing stack")?;
+        if stack.len() > stack_depth {
+            return Err(format!("Invalid save state: {} calls on a stack {} deep", stack.len(), stack_depth));
+        }
+        for frame in stack {
+            proc.stack.push(StackFrame {
+                return_address: number(&frame[0])? as u16,
+                call_site: number(&frame[1])? as usize,
+            });
+        }
+        proc.delay_timer.value = number(&state["delay_timer"])? as u8;
+        proc.sound_timer.value = number(&state["sound_timer"])? as u8;
+
+        let rng_kind = state["rng"]["kind"].as_str().and_then(RngKind::from_name).ok_or("Invalid save state: unknown RNG")?;
+        let rng_state = state["rng"]["state"].as_array().ok_or("Invalid save state: missing RNG state")?.iter().map(number).collect::<Result<Vec<u64>, String>>()?;
+        let mut rng = rng_kind.create(0);
+        if !rng.restore(&rng_state) {
+            return Err("Invalid save state: bad RNG state".to_string());
+        }
+
+        let width = number(&state["display"]["width"])? as usize;
+        let height = number(&state["display"]["height"])? as usize;
+        if !Display::is_supported(width, height) {
+            return Err(format!("Invalid save state: unsupported resolution {}x{}", width, height));
+        }
+        let mut display = Display::new();
+        display.set_resolution(width, height);
+        let pixels = bytes(&state["display"]["pixels"])?;
+        if pixels.len() != display.pixels.len() {
+            return Err("Invalid save state: display doesn't match its size".to_string());
+        }
+        display.pixels = pixels;
+        let rom_hash = state["rom_hash"].as_str().ok_or("Invalid save state: missing ROM hash")?.to_string();
+
+        // Everything checked out, nothing can fail from here on
+        self.platform = platform;
+        self.memory_map = memory_map;
+        self.stack_depth = stack_depth;
+        self.vip_stack = vip_stack;
+        self.display_wait = state["display_wait"].as_bool().unwrap_or(false);
+        self.instructions_per_frame = number(&state["instructions_per_frame"]).unwrap_or(10) as u32;
+        self.proc = proc;
+        let mut mem = Memory::new(memory_map.size, self.memory_policy);
+        mem.load_at_location(&memory, 0);
+        self.replace_memory(mem);
+        self.keypad = number(&state["keypad"]).unwrap_or(0) as u16;
+        self.rng = rng;
+        self.display = display;
+        self.rom = rom;
+        self.rom_hash = rom_hash;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.reset();
+        }
+        Ok(())
+    }
+
     pub fn prepare(&mut self) {
-        self.proc.program_counter = 0x200;
+        self.proc.program_counter = self.memory_map.entry_point;
     }
-    
-    pub fn clock(&mut self) {
-        if self.proc.program_counter >= 4094 {
-            eprintln!("Program counter exceeded 4095, max memory");
-            process::exit(1);
-        }
-
-        let mut thread_lock = self.thread_lock_mutex.lock().unwrap();
-        let mut thread_lock_copy = *thread_lock;
-        //while thread_lock_copy {
-        //    mem::drop(thread_lock);
-        //    sleep(Duration::from_millis(10));
-        //    thread_lock = self.thread_lock_mutex.lock().unwrap();
-        //    thread_lock_copy = *thread_lock;
-        //}
-        mem::drop(thread_lock);
+
+    // Runs one 60 Hz frame worth of instructions, then ticks the timers. Everything that
+    // happens in a frame only depends on the keypad state and the RNG seed
+    pub fn run_frame(&mut self) -> Result<(), EmulatorError> {
+        self.frame_ended = false;
+        self.polled_keys = 0;
+        for cheat in self.cheats.iter().filter(|cheat| cheat.freeze) {
+            cheat.apply(&mut self.mem);
+        }
+        if let (Some(script), hardware) = self.hardware() {
+            script.frame_start(hardware).map_err(EmulatorError::Script)?;
+        }
+        let mut executed = 0;
+        for _ in 0..self.instructions_per_frame {
+            self.clock()?;
+            executed += 1;
+            if self.frame_ended {
+                break;
+            }
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record_frame(executed, self.instructions_per_frame as u64, self.frame_ended);
+        }
         self.proc.delay_timer.clock();
+        self.proc.sound_timer.clock();
+        if let (Some(script), hardware) = self.hardware() {
+            script.frame_end(hardware).map_err(EmulatorError::Script)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_sound_playing(&self) -> bool {
+        self.proc.sound_timer.value > 0
+    }
+
+    // Tells which keys the program is looking for, to help find a game's controls
+    pub fn polled_keys(&self) -> u16 {
+        self.polled_keys
+    }
+
+    fn is_key_down(&self, key: u8) -> bool {
+        key < 16 && (self.keypad >> key) & 1 == 1
+    }
+    
+    // Called before resuming, so a breakpoint on the current instruction doesn't stop it again
+    pub fn step_over_breakpoint(&mut self) {
+        self.skip_breakpoint = true;
+    }
 
-        let opcode: u16 = self.mem.read_instruction(self.proc.program_counter);
-        self.proc.program_counter += 2;
+    pub fn clock(&mut self) -> Result<(), EmulatorError> {
+        let skip_breakpoint = std::mem::take(&mut self.skip_breakpoint);
+        if !skip_breakpoint && self.breakpoints.contains(&self.proc.program_counter) {
+            return Err(EmulatorError::Breakpoint(self.proc.program_counter));
+        }
+        // The script may move the PC somewhere else
+        if let (Some(script), hardware) = self.hardware() {
+            let address = hardware.proc.program_counter;
+            script.instruction(address, hardware).map_err(EmulatorError::Script)?;
+        }
+        let instruction_address = self.proc.program_counter;
+        let opcode = self.profiler.as_ref().map(|_| ((self.mem.peek(instruction_address) as u16) << 8) | self.mem.peek(instruction_address + 1) as u16);
+        let result = self.execute();
+        if let (Some(profiler), Some(opcode), Ok(())) = (self.profiler.as_mut(), opcode, &result) {
+            profiler.record_instruction(instruction_address, opcode);
+        }
+        // Breaking leaves the PC on the faulting instruction so it's what gets inspected
+        if result.as_ref().is_err_and(|e| e.is_debug_break()) {
+            self.proc.program_counter = instruction_address;
+        }
+        let writes = self.mem.take_writes();
+        if result.is_ok() {
+            if let (Some(script), hardware) = self.hardware() {
+                script.writes(writes, hardware).map_err(EmulatorError::Script)?;
+            }
+        }
+        // Watchpoints stop once the instruction is done, which is what debuggers expect
+        match self.mem.take_watch_hit() {
+            Some((address, kind)) if result.is_ok() => Err(EmulatorError::Watchpoint { address, kind }),
+            _ => result,
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), EmulatorError> {
+        let instruction_address = self.proc.program_counter;
+        let opcode: u16 = self.mem.read_instruction(self.proc.program_counter)?;
+        // Keeps the PC in memory when wrapping, otherwise the fetch above already faulted
+        self.proc.program_counter = (self.proc.program_counter + 2) % self.mem.size();
 
         //println!("Fetched instruction: {:04X}", opcode);
         //for (it, val) in self.proc.registers.iter().enumerate() {
@@ -63,12 +513,21 @@ impl Emulator {
 
         match opcode {
             0x00E0 => { // Clear the screen
-                let mut renderer = self.renderer_mutex.lock().unwrap();
-                (*renderer).clear_pixels();
+                self.display.clear_pixels();
             }
 
             0x00EE => { // Return out of subroutine
-                self.proc.program_counter = self.proc.pop_stack() as usize;
+                let depth = self.proc.stack.len();
+                let Some(frame) = self.proc.pop_stack() else {
+                    return Err(EmulatorError::StackUnderflow(instruction_address));
+                };
+                // ROMs that poke at the VIP stack get to change where they return to
+                self.proc.program_counter = if self.vip_stack {
+                    let slot = Self::vip_stack_slot(depth);
+                    ((self.mem.read_data(slot)? as usize) << 8) | self.mem.read_data(slot + 1)? as usize
+                } else {
+                    frame.return_address as usize
+                };
             }
 
             _ if (opcode & 0xF000) == 0x1000 => { // Jump to address
@@ -78,7 +537,15 @@ impl Emulator {
 
             _ if (opcode & 0xF000) == 0x2000 => { // Calls subroutine at address
                 let address = opcode & 0x0FFF;
-                self.proc.push_stack(self.proc.program_counter as u16);
+                let return_address = self.proc.program_counter as u16;
+                if !self.proc.push_stack(StackFrame { return_address, call_site: instruction_address }) {
+                    return Err(EmulatorError::StackOverflow(instruction_address));
+                }
+                if self.vip_stack {
+                    let slot = Self::vip_stack_slot(self.proc.stack.len());
+                    self.mem.write_data(slot, (return_address >> 8) as u8)?;
+                    self.mem.write_data(slot + 1, return_address as u8)?;
+                }
                 self.proc.program_counter = address as usize;
             }
 
@@ -119,7 +586,6 @@ impl Emulator {
             _ if (opcode & 0xF000) == 0x7000 => { 
                 let register_value = self.proc.get_register(((opcode >> 8) & 0x0F) as u8);
                 let value = register_value.wrapping_add(opcode as u8);
-                println!("{} + {} = {}", register_value, opcode as u8, value);
                 self.proc.set_register(((opcode >> 8) & 0x0F) as u8, value);
             }
 
@@ -223,7 +689,7 @@ impl Emulator {
             
             // generate random number to register Vx and perform an & operation on it
             _ if (opcode & 0xF000) == 0xC000 => {
-                let random_num: u8 = random();
+                let random_num: u8 = self.rng.next_byte();
                 self.proc.set_register(((opcode >> 8) & 0x0F) as u8, random_num & (opcode & 0x00FF) as u8);
             }
 
@@ -232,23 +698,28 @@ impl Emulator {
                 let x = self.proc.get_register(((opcode >> 8) & 0x0F) as u8);
                 let y = self.proc.get_register(((opcode >> 4) & 0x0F) as u8);
                 let height = (opcode & 0x0F) as u8;
-                let sprite_begin = self.proc.address_register;
-                let sprite_end = self.proc.address_register + height as u16;
+                let sprite_begin = self.proc.address_register as usize;
+                let sprite_end = sprite_begin + height as usize;
 
                 let mut sprite = Vec::<u8>::new();
                 for i in sprite_begin..sprite_end {
-                    sprite.push(self.mem.read_data(i as usize));
-                } 
+                    sprite.push(self.mem.read_data(i)?);
+                }
                 
-                let mut renderer = self.renderer_mutex.lock().unwrap();
-                self.proc.set_register(0xF, if (*renderer).draw_sprite(x, y, sprite) {1} else {0});
+                let collision = self.display.draw_sprite(x, y, sprite);
+                self.proc.set_register(0xF, if collision {1} else {0});
+                // On the VIP the sprite routine waited for the vertical blank interrupt, so
+                // nothing else runs until the next frame
+                if self.display_wait {
+                    self.frame_ended = true;
+                }
             }
 
             // skips if key in Vx is pressed
             _ if (opcode & 0xF0FF) == 0xE09E => {
                 let register_key = self.proc.get_register(((opcode >> 8) & 0x0F) as u8);
-                let mut keyboard = self.keyboard_mutex.lock().unwrap(); 
-                if register_key == keyboard.get_hexkey_pressed() {
+                self.polled_keys |= 1u16.checked_shl(register_key as u32).unwrap_or(0);
+                if self.is_key_down(register_key) {
                     self.proc.program_counter += 2;
                 }
             }
@@ -256,8 +727,8 @@ impl Emulator {
             // skips if key in Vx isnt pressed
             _ if (opcode & 0xF0FF) == 0xE0A1 => {
                 let register_key = self.proc.get_register(((opcode >> 8) & 0x0F) as u8);
-                let mut keyboard = self.keyboard_mutex.lock().unwrap(); 
-                if register_key != keyboard.get_hexkey_pressed() {
+                self.polled_keys |= 1u16.checked_shl(register_key as u32).unwrap_or(0);
+                if !self.is_key_down(register_key) {
                     self.proc.program_counter += 2;
                 }
             }
@@ -269,14 +740,15 @@ impl Emulator {
 
             // sets the value of Vx to the pressed key, wait for key press
             _ if (opcode & 0xF0FF) == 0xF00A => {
-                let mut keyboard = self.keyboard_mutex.lock().unwrap(); 
-                let mut key = keyboard.get_hexkey_pressed();
-                while key > 15 {
-                    mem::drop(keyboard);
-                    keyboard = self.keyboard_mutex.lock().unwrap();
-                    key = keyboard.get_hexkey_pressed();
+                match (0..16).find(|key| self.is_key_down(*key)) {
+                    Some(key) => {
+                        self.proc.set_register(((opcode >> 8) & 0x0F) as u8, key);
+                    }
+                    None => {
+                        // Nothing held yet, run this instruction again until a key is pressed
+                        self.proc.program_counter = instruction_address;
+                    }
                 }
-                self.proc.set_register(((opcode >> 8) & 0x0F) as u8, keyboard.get_hexkey_pressed());
             }
             
             // sets the value of the delay timer to the value in Vx
@@ -286,11 +758,12 @@ impl Emulator {
 
             // sets the value of the sound timer to the value of Vx
             _ if (opcode & 0xF0FF) == 0xF018 => {
+                self.proc.sound_timer.value = self.proc.get_register(((opcode >> 8) & 0x0F) as u8);
             }
 
             // adds the value of Vx to I
             _ if (opcode & 0xF0FF) == 0xF01E => {
-                self.proc.address_register += self.proc.get_register(((opcode >> 8) & 0x0F) as u8) as u16;
+                self.proc.address_register = self.proc.address_register.wrapping_add(self.proc.get_register(((opcode >> 8) & 0x0F) as u8) as u16);
             }
 
             // sets I to the location of Vx font sprite
@@ -301,9 +774,9 @@ impl Emulator {
             // write value of Vx BCD coded at I
             _ if (opcode & 0xF0FF) == 0xF033 => {
                 let register_x_value = self.proc.get_register(((opcode >> 8) & 0x0F) as u8);
-                self.mem.write_data(self.proc.address_register as usize, register_x_value / 100);
-                self.mem.write_data(self.proc.address_register as usize + 1, (register_x_value / 10) % 10);
-                self.mem.write_data(self.proc.address_register as usize + 2, register_x_value % 10);
+                self.mem.write_data(self.proc.address_register as usize, register_x_value / 100)?;
+                self.mem.write_data(self.proc.address_register as usize + 1, (register_x_value / 10) % 10)?;
+                self.mem.write_data(self.proc.address_register as usize + 2, register_x_value % 10)?;
             }
 
             // write all registers up to Vx to memory at I
@@ -312,24 +785,245 @@ impl Emulator {
                 let register_data_array = self.proc.get_registers(end_index);
 
                 for i in 0..(end_index+1) {
-                    self.mem.write_data((self.proc.address_register + i as u16) as usize, register_data_array[i as usize]);
+                    self.mem.write_data(self.proc.address_register as usize + i as usize, register_data_array[i as usize])?;
                 }
             }
 
             _ if (opcode & 0xF0FF) == 0xF065 => {
                 let end_index = ((opcode >> 8) & 0x0F) as u8;
                 for i in 0..(end_index+1) {
-                    self.proc.set_register(i, self.mem.read_data((self.proc.address_register + i as u16) as usize));
+                    self.proc.set_register(i, self.mem.read_data(self.proc.address_register as usize + i as usize)?);
+                }
+            }
+
+            // save registers up to Vx into the RPL user flags, which are kept on disk
+            _ if (opcode & 0xF0FF) == 0xF075 => {
+                let count = (((opcode >> 8) & 0x0F) as usize + 1).min(self.rpl_flag_count());
+                if self.rpl_flags[..count] != self.proc.registers[..count] {
+                    self.rpl_flags[..count].copy_from_slice(&self.proc.registers[..count]);
+                    self.save_rpl_flags();
                 }
             }
 
+            // load registers up to Vx from the RPL user flags
+            _ if (opcode & 0xF0FF) == 0xF085 => {
+                let count = (((opcode >> 8) & 0x0F) as usize + 1).min(self.rpl_flag_count());
+                self.proc.registers[..count].copy_from_slice(&self.rpl_flags[..count]);
+            }
+
             _default => {
-                println!("Potentially unknown opcode? {:04X}", opcode);
+                self.messages.push(format!("Potentially unknown opcode? {:04X}", opcode));
             }
         }
-        thread_lock = self.thread_lock_mutex.lock().unwrap();
-        *thread_lock = true;
-        mem::drop(thread_lock);
-        thread::sleep(Duration::from_secs_f64(1.0 / 600.0));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(emu: &mut Emulator, instructions: usize) {
+        for _ in 0..instructions {
+            assert!(emu.clock().is_ok());
+        }
+    }
+
+    fn load(program: &[u16], policy: MemoryPolicy) -> Emulator {
+        let mut emu = Emulator::new();
+        emu.memory_policy = policy;
+        emu.rom = program.iter().flat_map(|opcode| opcode.to_be_bytes()).collect();
+        emu.reset();
+        emu
+    }
+
+    fn memory_fault(result: Result<(), EmulatorError>) -> Option<(usize, AccessKind, bool)> {
+        match result {
+            Err(EmulatorError::MemoryFault(fault)) => Some((fault.address, fault.kind, fault.debug_break)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn saving_registers_past_the_end_faults() {
+        let mut emu = load(&[0x6001, 0x6102, 0x6203, 0xAFFE, 0xF255], MemoryPolicy::Fault);
+        run(&mut emu, 4);
+        assert_eq!(memory_fault(emu.clock()), Some((0x1000, AccessKind::Write, false)));
+    }
+
+    #[test]
+    fn decimal_wraps_around() {
+        let mut emu = load(&[0x60FF, 0xAFFE, 0xF033], MemoryPolicy::Wrap);
+        run(&mut emu, 3);
+        assert_eq!((emu.mem.peek(0xFFE), emu.mem.peek(0xFFF), emu.mem.peek(0x000)), (2, 5, 5));
+    }
+
+    #[test]
+    fn sprites_past_the_end_break() {
+        let mut emu = load(&[0xAFFE, 0xD014], MemoryPolicy::Break);
+        run(&mut emu, 1);
+        assert_eq!(memory_fault(emu.clock()), Some((0x1000, AccessKind::Read, true)));
+        // Left on the DXYN so it's what gets inspected
+        assert_eq!(emu.proc.program_counter, 0x202);
+    }
+
+    #[test]
+    fn index_overflow() {
+        // I ends up at 10FE, which wraps to FE
+        let program = [0x60FF, 0xAFFF, 0xF01E, 0xF065];
+        let mut emu = load(&program, MemoryPolicy::Wrap);
+        emu.mem.poke(0x0FE, 0x42);
+        run(&mut emu, 4);
+        assert_eq!((emu.proc.address_register, emu.proc.registers[0]), (0x10FE, 0x42));
+
+        let mut emu = load(&program, MemoryPolicy::Fault);
+        run(&mut emu, 3);
+        assert_eq!(memory_fault(emu.clock()), Some((0x10FE, AccessKind::Read, false)));
+    }
+
+    #[test]
+    fn stack_overflows_at_its_depth() {
+        for stack_depth in [1, 3, 12] {
+            // Calls itself until the stack runs out
+            let mut emu = load(&[0x6000, 0x2202], MemoryPolicy::Fault);
+            emu.stack_depth = stack_depth;
+            emu.reset();
+            run(&mut emu, 1 + stack_depth);
+            assert_eq!(emu.call_stack().len(), stack_depth);
+            assert!(matches!(emu.clock(), Err(EmulatorError::StackOverflow(0x202))));
+        }
+    }
+
+    #[test]
+    fn return_without_a_call_underflows() {
+        let mut emu = load(&[0x6000, 0x00EE], MemoryPolicy::Fault);
+        run(&mut emu, 1);
+        assert!(matches!(emu.clock(), Err(EmulatorError::StackUnderflow(0x202))));
+    }
+
+    #[test]
+    fn call_sites() {
+        let mut emu = load(&[0x2204, 0x1202, 0x2208, 0x00EE, 0x00EE], MemoryPolicy::Fault);
+        run(&mut emu, 2);
+        let frames: Vec<(usize, u16)> = emu.call_stack().iter().map(|frame| (frame.call_site, frame.return_address)).collect();
+        assert_eq!(frames, vec![(0x200, 0x202), (0x204, 0x206)]);
+        run(&mut emu, 3);
+        assert!(emu.call_stack().is_empty());
+        assert_eq!(emu.proc.program_counter, 0x202);
+    }
+
+    #[test]
+    fn vip_stack_in_memory() {
+        let mut emu = load(&[0x2204, 0x1202, 0x2208, 0x00EE, 0x00EE], MemoryPolicy::Fault);
+        emu.vip_stack = true;
+        run(&mut emu, 2);
+        assert_eq!((emu.mem.peek(0xECE), emu.mem.peek(0xECF)), (0x02, 0x02));
+        assert_eq!((emu.mem.peek(0xECC), emu.mem.peek(0xECD)), (0x02, 0x06));
+
+        // The innermost call returns wherever the patched slot says
+        emu.mem.poke(0xECC, 0x03);
+        emu.mem.poke(0xECD, 0x10);
+        run(&mut emu, 1);
+        assert_eq!(emu.proc.program_counter, 0x310);
+        assert_eq!(emu.call_stack().len(), 1);
+    }
+
+    // A state with one field replaced
+    fn edited_state(emu: &Emulator, field: &str, value: Value) -> String {
+        let mut state: Value = serde_json::from_str(&emu.save_state()).unwrap();
+        state[field] = value;
+        state.to_string()
+    }
+
+    fn running_emulator() -> Emulator {
+        let mut emu = load(&[0x2204, 0x1202, 0x602A, 0xC1FF, 0x00EE], MemoryPolicy::Fault);
+        // Stops right before the return
+        run(&mut emu, 3);
+        emu
+    }
+
+    #[test]
+    fn states_round_trip() {
+        let emu = running_emulator();
+        let state = emu.save_state();
+        let mut loaded = Emulator::new();
+        assert!(loaded.load_state(&state).is_ok());
+        assert_eq!(loaded.save_state(), state);
+        assert_eq!((loaded.proc.program_counter, loaded.proc.registers[0]), (0x208, 0x2A));
+        assert_eq!(loaded.call_stack().len(), 1);
+
+        // Both go on with the same random numbers
+        let mut emu = emu;
+        run(&mut emu, 2);
+        run(&mut loaded, 2);
+        assert_eq!(loaded.save_state(), emu.save_state());
+        assert!(loaded.load_state(&state).is_ok());
+        loaded.reset();
+        assert_eq!(loaded.proc.program_counter, 0x200);
+    }
+
+    #[test]
+    fn states_that_dont_fit_are_refused() {
+        let emu = running_emulator();
+        let mut loaded = Emulator::new();
+        let tiny = {
+            let mut state: Value = serde_json::from_str(&emu.save_state()).unwrap();
+            state["memory_size"] = json!(2);
+            state["memory"] = json!("0000");
+            state.to_string()
+        };
+        assert!(loaded.load_state(&tiny).is_err());
+        assert!(loaded.load_state(&edited_state(&emu, "memory_size", json!(0))).is_err());
+        assert!(loaded.load_state(&edited_state(&emu, "load_address", json!(0xFFF))).is_err());
+        assert!(loaded.load_state(&edited_state(&emu, "pc", json!(0x1000))).is_err());
+        assert!(loaded.load_state(&edited_state(&emu, "stack_depth", json!(0))).is_err());
+        let calls: Vec<Value> = (0..13).map(|_| json!([0x202, 0x200])).collect();
+        assert!(loaded.load_state(&edited_state(&emu, "stack", json!(calls))).is_err());
+        let mut state: Value = serde_json::from_str(&edited_state(&emu, "vip_stack", json!(true))).unwrap();
+        state["stack_depth"] = json!(VIP_STACK_SLOTS + 1);
+        assert!(loaded.load_state(&state.to_string()).is_err());
+
+        // Nothing was touched, a reset still works
+        loaded.reset();
+        assert_eq!(loaded.mem.size(), 0x1000);
+    }
+
+    #[test]
+    fn rpl_flags_are_kept_per_rom() {
+        let dir = std::env::temp_dir().join(format!("chip8-rpl-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("flags.ch8");
+        // LD V0, 05; LD V1, 0A; LD R, V1; LD V0, [R] after a reset
+        fs::write(&rom_path, [0x60, 0x05, 0x61, 0x0A, 0xF1, 0x75, 0xF1, 0x85]).unwrap();
+        let rom_path = rom_path.to_str().unwrap();
+
+        let mut emu = Emulator::new();
+        emu.set_platform(Platform::SuperChip);
+        emu.flags_dir = Some(dir.to_str().unwrap().to_string());
+        assert!(emu.load_rom(rom_path).is_ok());
+        run(&mut emu, 3);
+        let saved = fs::read(dir.join(format!("{}.rpl", emu.rom_hash())));
+
+        // A new run of the same ROM loads them before anything is saved
+        let mut emu = Emulator::new();
+        emu.set_platform(Platform::SuperChip);
+        emu.flags_dir = Some(dir.to_str().unwrap().to_string());
+        assert!(emu.load_rom(rom_path).is_ok());
+        emu.proc.program_counter += 6;
+        run(&mut emu, 1);
+        let restored = (emu.proc.registers[0], emu.proc.registers[1]);
+
+        // Without a flags directory nothing is read or written
+        let mut emu = Emulator::new();
+        emu.set_platform(Platform::SuperChip);
+        assert!(emu.load_rom(rom_path).is_ok());
+        emu.proc.program_counter += 6;
+        run(&mut emu, 1);
+        let cleared = (emu.proc.registers[0], emu.proc.registers[1]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(saved.unwrap(), vec![0x05, 0x0A, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(restored, (0x05, 0x0A));
+        assert_eq!(cleared, (0, 0));
     }
 }
@@ -0,0 +1,209 @@
+// Controls in the order of their bits, named like in mappings
+const CONTROLS: [&str; 12] = ["up", "down", "left", "right", "a", "b", "x", "y", "l", "r", "select", "start"];
+
+// Which hex key each control presses
+#[derive(Clone, Copy)]
+pub struct GamepadMapping {
+    keys: [Option<u8>; 12],
+}
+
+impl Default for GamepadMapping {
+    // Same keys as WASD plus Q and E on the keyboard layout most ROMs are written for
+    fn default() -> Self {
+        GamepadMapping::parse("up:5,down:8,left:7,right:9,a:6,b:4").unwrap()
+    }
+}
+
+impl GamepadMapping {
+    // Comma separated `<control>:<hex key>` pairs, controls left out do nothing
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut keys = [None; 12];
+        for pair in value.split(',') {
+            let (control, key) = pair.split_once(':').ok_or_else(|| format!("Expected <control>:<key>, got {}", pair))?;
+            let index = CONTROLS.iter().position(|name| *name == control).ok_or_else(|| format!("Unknown control {}, expected one of {}", control, CONTROLS.join(", ")))?;
+            let key = u8::from_str_radix(key, 16).ok().filter(|key| *key < 16).ok_or_else(|| format!("Invalid key {}, expected 0-F", key))?;
+            keys[index] = Some(key);
+        }
+        Ok(GamepadMapping { keys })
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn hex_keys(&self, controls: u16) -> u16 {
+        self.keys.iter().enumerate().filter(|(control, _)| (controls >> control) & 1 == 1).filter_map(|(_, key)| *key).fold(0, |keys, key| keys | (1 << key))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use joystick::Gamepad;
+
+// Only the Linux joystick API is read, elsewhere a gamepad never presses anything
+#[cfg(not(target_os = "linux"))]
+pub struct Gamepad;
+
+#[cfg(not(target_os = "linux"))]
+impl Gamepad {
+    pub fn open(_mapping: GamepadMapping) -> Self {
+        Gamepad
+    }
+
+    pub fn keys(&self) -> u16 {
+        0
+    }
+
+    pub fn take_messages(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod joystick {
+    use super::GamepadMapping;
+    use std::fs::{File, OpenOptions};
+    use std::io::{ErrorKind, Read};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    const UP: u16 = 1;
+    const DOWN: u16 = 1 << 1;
+    const LEFT: u16 = 1 << 2;
+    const RIGHT: u16 = 1 << 3;
+
+    // Sticks count as pushed a bit under halfway
+    const AXIS_THRESHOLD: i16 = 16000;
+
+    // How often the device is read, well under a frame
+    const READ_INTERVAL: Duration = Duration::from_millis(4);
+    const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+    // Reads the first joystick the Linux joystick API offers from a thread of its own, picking
+    // one up whenever it's plugged in. The thread stops when the gamepad is dropped
+    pub struct Gamepad {
+        controls: Arc<AtomicU16>,
+        mapping: GamepadMapping,
+        stop: Option<Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+        messages: Receiver<String>, // Pads coming and going
+    }
+
+    // Waits on the stop channel, true once the gamepad was dropped
+    fn stopped(stop: &Receiver<()>, timeout: Duration) -> bool {
+        !matches!(stop.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+    }
+
+    impl Gamepad {
+        pub fn open(mapping: GamepadMapping) -> Self {
+            let controls = Arc::new(AtomicU16::new(0));
+            let shared = controls.clone();
+            let (stop, stop_receiver) = mpsc::channel();
+            let (message_sender, messages) = mpsc::channel();
+            let thread = thread::spawn(move || loop {
+                // Non-blocking so the thread can notice it should stop while the pad sits idle
+                if let Some((path, device)) = (0..4).find_map(|index| {
+                    let path = format!("/dev/input/js{}", index);
+                    OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&path).ok().map(|device| (path, device))
+                }) {
+                    let _ = message_sender.send(format!("Gamepad connected: {}", path));
+                    read_events(device, &shared, &stop_receiver);
+                    shared.store(0, Ordering::Relaxed);
+                    let _ = message_sender.send(format!("Gamepad disconnected: {}", path));
+                }
+                if stopped(&stop_receiver, SCAN_INTERVAL) {
+                    return;
+                }
+            });
+            Gamepad { controls, mapping, stop: Some(stop), thread: Some(thread), messages }
+        }
+
+        pub fn keys(&self) -> u16 {
+            self.mapping.hex_keys(self.controls.load(Ordering::Relaxed))
+        }
+
+        pub fn take_messages(&self) -> Vec<String> {
+            self.messages.try_iter().collect()
+        }
+    }
+
+    impl Drop for Gamepad {
+        fn drop(&mut self) {
+            drop(self.stop.take());
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    // Events are 8 bytes: a timestamp, the value, the type (button or axis, with a flag for the
+    // initial state sent on open) and the button or axis number. Buttons follow the xpad layout,
+    // which most pads use, and both the left stick and the D-pad hat move
+    fn read_events(mut device: File, controls: &AtomicU16, stop: &Receiver<()>) {
+        let mut buttons = 0u16;
+        let mut axes = [0i16; 8];
+        let mut event = [0u8; 8];
+        loop {
+            // The driver hands out whole events
+            match device.read(&mut event) {
+                Ok(8) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if stopped(stop, READ_INTERVAL) {
+                        return;
+                    }
+                    continue;
+                }
+                _ => return,
+            }
+            let value = i16::from_le_bytes([event[4], event[5]]);
+            let number = event[7] as usize;
+            match event[6] & 0x7F {
+                1 if number < 8 => {
+                    // A, B, X, Y, LB, RB, back, start
+                    let bit = 1 << (number + 4);
+                    buttons = if value != 0 { buttons | bit } else { buttons & !bit };
+                }
+                2 if number < axes.len() => axes[number] = value,
+                _ => continue,
+            }
+
+            let pushed = |axis: usize, direction: i16| axes[axis].saturating_mul(direction) > AXIS_THRESHOLD;
+            let mut directions = 0;
+            for (x, y) in [(0, 1), (6, 7)] {
+                directions |= if pushed(y, -1) { UP } else { 0 } | if pushed(y, 1) { DOWN } else { 0 };
+                directions |= if pushed(x, -1) { LEFT } else { 0 } | if pushed(x, 1) { RIGHT } else { 0 };
+            }
+            controls.store(buttons | directions, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings() {
+        let mapping = GamepadMapping::parse("up:2,a:F,start:0").unwrap();
+        assert_eq!(mapping.hex_keys(0), 0);
+        assert_eq!(mapping.hex_keys(1), 1 << 2);
+        // A, start and a control without a key
+        assert_eq!(mapping.hex_keys(1 << 4 | 1 << 11 | 1 << 5), 1 << 0xF | 1);
+    }
+
+    #[test]
+    fn default_mapping() {
+        let mapping = GamepadMapping::default();
+        let keys: Vec<u16> = (0..6).map(|control| mapping.hex_keys(1 << control)).collect();
+        assert_eq!(keys, vec![1 << 5, 1 << 8, 1 << 7, 1 << 9, 1 << 6, 1 << 4]);
+        assert_eq!(mapping.hex_keys(1 << 6), 0);
+    }
+
+    #[test]
+    fn bad_mappings() {
+        assert!(GamepadMapping::parse("trigger:5").err().unwrap().starts_with("Unknown control trigger"));
+        assert_eq!(GamepadMapping::parse("a:10").err(), Some("Invalid key 10, expected 0-F".to_string()));
+        assert_eq!(GamepadMapping::parse("a:G").err(), Some("Invalid key G, expected 0-F".to_string()));
+        assert_eq!(GamepadMapping::parse("a5").err(), Some("Expected <control>:<key>, got a5".to_string()));
+    }
+}
@@ -0,0 +1,486 @@
+use crate::emulator::*;
+use crate::hex;
+use crate::memory::*;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+// Register layout GDB is told about, in the order of the g packet
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// Bytes per register, V0-VF then I, PC, SP, DT and ST
+const REGISTER_SIZES: [usize; 21] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1];
+const PC_REGISTER: usize = 17;
+
+// Things a packet asks for that the controller has to take care of, the rest is answered here
+pub enum GdbAction {
+    Attached,
+    Continue,
+    Step,
+    Interrupt,
+    Detached,
+}
+
+// Speaks the GDB remote serial protocol over TCP, one debugger at a time. Polled by the emulator
+// thread, so nothing here ever blocks for long
+pub struct GdbStub {
+    listener: TcpListener,
+    connection: Option<TcpStream>,
+    buffer: Vec<u8>,
+    waiting: bool, // GDB continued or stepped and is owed a stop reply
+    pub read_only: bool, // Writes are refused, a movie is being recorded or played
+}
+
+// What came in over the connection, one packet or interrupt at a time
+#[derive(PartialEq, Debug)]
+enum Received {
+    Interrupt,
+    Packet(String),
+    Corrupted, // Checksum didn't match, GDB sends it again
+}
+
+// Packets are `$<data>#<2 hex digits>`, the checksum being the sum of the data bytes
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Takes the next complete packet or interrupt off the buffer. Returns None while the rest of a
+// packet is still on its way
+fn take_packet(buffer: &mut Vec<u8>) -> Option<Received> {
+    while let Some(&first) = buffer.first() {
+        match first {
+            0x03 => {
+                buffer.remove(0);
+                return Some(Received::Interrupt);
+            }
+            b'$' => {
+                // Wait for the rest of the packet, including the 2 checksum digits
+                let end = buffer.iter().position(|byte| *byte == b'#')?;
+                if buffer.len() < end + 3 {
+                    return None;
+                }
+                let packet: Vec<u8> = buffer.drain(..end + 3).collect();
+                let data = &packet[1..end];
+                let digits = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                if digits != Some(checksum(data)) {
+                    return Some(Received::Corrupted);
+                }
+                return Some(Received::Packet(String::from_utf8_lossy(data).into_owned()));
+            }
+            _ => {
+                buffer.remove(0); // Acks and noise
+            }
+        }
+    }
+    None
+}
+
+fn parse_hex(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+// Stop reply for whatever paused or halted the program
+fn stop_reply(reason: Option<&EmulatorError>) -> String {
+    match reason {
+        None | Some(EmulatorError::Breakpoint(_)) => "S05".to_string(), // SIGTRAP
+        Some(EmulatorError::Watchpoint { address, kind }) => {
+            let watch = if *kind == AccessKind::Write { "watch" } else { "rwatch" };
+            format!("T05{}:{:x};", watch, address)
+        }
+        Some(_) => "S0b".to_string(), // SIGSEGV
+    }
+}
+
+fn register_value(emu: &Emulator, register: usize) -> Option<usize> {
+    match register {
+        0..=15 => Some(emu.proc.registers[register] as usize),
+        16 => Some(emu.proc.address_register as usize),
+        PC_REGISTER => Some(emu.proc.program_counter),
+        18 => Some(emu.proc.stack.len()),
+        19 => Some(emu.proc.delay_timer.value as usize),
+        20 => Some(emu.proc.sound_timer.value as usize),
+        _ => None,
+    }
+}
+
+// The stack pointer follows the call stack and can't be written
+fn set_register(emu: &mut Emulator, register: usize, value: usize) -> bool {
+    match register {
+        0..=15 => emu.proc.registers[register] = value as u8,
+        16 => emu.proc.address_register = value as u16,
+        PC_REGISTER => emu.proc.program_counter = value % emu.mem.size(),
+        18 => {}
+        19 => emu.proc.delay_timer.value = value as u8,
+        20 => emu.proc.sound_timer.value = value as u8,
+        _ => return false,
+    }
+    true
+}
+
+// Registers go over the wire little endian
+fn encode_register(emu: &Emulator, register: usize) -> String {
+    let value = register_value(emu, register).unwrap_or(0);
+    hex::encode(&value.to_le_bytes()[..REGISTER_SIZES[register]])
+}
+
+fn decode_register(bytes: &[u8]) -> usize {
+    bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as usize)
+}
+
+impl GdbStub {
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            connection: None,
+            buffer: Vec::new(),
+            waiting: false,
+            read_only: false,
+        })
+    }
+
+    // Accepts a debugger and handles whatever it sent since the last poll
+    pub fn poll(&mut self, emu: &mut Emulator) -> Vec<GdbAction> {
+        let mut actions = Vec::new();
+        if self.connection.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    // Reads give up almost right away so the emulator thread keeps going
+                    if stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(1)))).is_err() {
+                        return actions;
+                    }
+                    let _ = stream.set_nodelay(true);
+                    self.connection = Some(stream);
+                    self.buffer.clear();
+                    self.waiting = false;
+                    actions.push(GdbAction::Attached);
+                }
+                Err(_) => return actions,
+            }
+        }
+
+        let mut chunk = [0; 4096];
+        let received = match self.connection.as_mut().unwrap().read(&mut chunk) {
+            Ok(0) => None,
+            Ok(length) => Some(length),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Some(0),
+            Err(_) => None,
+        };
+        let Some(length) = received else {
+            self.connection = None;
+            actions.push(GdbAction::Detached);
+            return actions;
+        };
+        self.buffer.extend_from_slice(&chunk[..length]);
+
+        while let Some(received) = take_packet(&mut self.buffer) {
+            match received {
+                Received::Interrupt => actions.push(GdbAction::Interrupt),
+                Received::Corrupted => self.send_raw(b"-"),
+                Received::Packet(packet) => {
+                    self.send_raw(b"+");
+                    let (reply, action) = self.handle_packet(&packet, emu);
+                    if let Some(reply) = reply {
+                        self.send_packet(&reply);
+                    }
+                    actions.extend(action);
+                }
+            }
+        }
+        // Killed, or a reply couldn't be sent
+        if self.connection.is_none() {
+            actions.push(GdbAction::Detached);
+        }
+        actions
+    }
+
+    // Tells a debugger waiting on a continue or step why the program stopped
+    pub fn report_stop(&mut self, reason: Option<&EmulatorError>) {
+        if self.waiting {
+            self.waiting = false;
+            self.send_packet(&stop_reply(reason));
+        }
+    }
+
+    // The program crashed earlier and can't go anywhere until it's reset
+    pub fn report_halted(&mut self) {
+        if self.waiting {
+            self.waiting = false;
+            self.send_packet("S0b");
+        }
+    }
+
+    // The program was paused from somewhere else than the debugger
+    pub fn report_interrupt(&mut self) {
+        if self.waiting {
+            self.waiting = false;
+            self.send_packet("S02"); // SIGINT
+        }
+    }
+
+    fn send_raw(&mut self, bytes: &[u8]) {
+        if let Some(connection) = self.connection.as_mut() {
+            if connection.write_all(bytes).is_err() {
+                self.connection = None;
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) {
+        self.send_raw(format!("${}#{:02x}", data, checksum(data.as_bytes())).as_bytes());
+    }
+
+    // Returns the reply to send, if any, and what the controller has to do
+    fn handle_packet(&mut self, packet: &str, emu: &mut Emulator) -> (Option<String>, Option<GdbAction>) {
+        let mut action = None;
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => stop_reply(None),
+            Some(b'g') => (0..REGISTER_SIZES.len()).map(|register| encode_register(emu, register)).collect(),
+            Some(b'G' | b'P' | b'M') if self.read_only => "E01".to_string(),
+            Some(b'G') => match hex::decode(&packet[1..]) {
+                Some(bytes) if bytes.len() >= REGISTER_SIZES.iter().sum() => {
+                    let mut offset = 0;
+                    for (register, size) in REGISTER_SIZES.iter().enumerate() {
+                        set_register(emu, register, decode_register(&bytes[offset..offset + size]));
+                        offset += size;
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            Some(b'p') => match parse_hex(&packet[1..]).filter(|register| *register < REGISTER_SIZES.len()) {
+                Some(register) => encode_register(emu, register),
+                None => "E01".to_string(),
+            },
+            Some(b'P') => {
+                let written = packet[1..].split_once('=').and_then(|(register, value)| {
+                    let register = parse_hex(register)?;
+                    Some(set_register(emu, register, decode_register(&hex::decode(value)?)))
+                });
+                if written == Some(true) { "OK".to_string() } else { "E01".to_string() }
+            }
+            Some(b'm') => {
+                let range = packet[1..].split_once(',').and_then(|(address, length)| Some((parse_hex(address)?, parse_hex(length)?)));
+                // Addresses near the top of usize would wrap around, those are just bad packets
+                match range.and_then(|(address, length)| Some((address, address.checked_add(length)?))) {
+                    Some((address, end)) if end <= emu.mem.size() => hex::encode(&(address..end).map(|address| emu.mem.peek(address)).collect::<Vec<u8>>()),
+                    Some(_) => "E14".to_string(), // EFAULT
+                    None => "E01".to_string(),
+                }
+            }
+            Some(b'M') => {
+                let write = packet[1..].split_once(':').and_then(|(range, data)| {
+                    let (address, _) = range.split_once(',')?;
+                    Some((parse_hex(address)?, hex::decode(data)?))
+                });
+                match write.map(|(address, bytes)| (address, address.checked_add(bytes.len()), bytes)) {
+                    Some((address, Some(end), bytes)) if end <= emu.mem.size() => {
+                        for (offset, byte) in bytes.iter().enumerate() {
+                            emu.mem.poke(address + offset, *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    Some((_, Some(_), _)) => "E14".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            Some(b'c') | Some(b's') => {
+                // Optionally resumes somewhere else
+                if let Some(address) = parse_hex(&packet[1..]).filter(|_| !self.read_only) {
+                    set_register(emu, PC_REGISTER, address);
+                }
+                self.waiting = true;
+                return (None, Some(if packet.starts_with('c') { GdbAction::Continue } else { GdbAction::Step }));
+            }
+            Some(b'Z') | Some(b'z') => self.handle_breakpoint(packet, emu),
+            Some(b'D') => {
+                action = Some(GdbAction::Detached);
+                "OK".to_string()
+            }
+            Some(b'k') => {
+                self.connection = None; // Noticed at the end of the poll
+                return (None, None);
+            }
+            Some(b'H') => "OK".to_string(),
+            _ if packet.starts_with("qSupported") => "PacketSize=1000;qXfer:features:read+".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let range = packet["qXfer:features:read:target.xml:".len()..].split_once(',').and_then(|(offset, length)| Some((parse_hex(offset)?, parse_hex(length)?)));
+                match range {
+                    Some((offset, length)) if offset < TARGET_XML.len() => {
+                        let end = (offset + length).min(TARGET_XML.len());
+                        let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                        format!("{}{}", more, &TARGET_XML[offset..end])
+                    }
+                    Some(_) => "l".to_string(),
+                    None => "E01".to_string(),
+                }
+            }
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet == "qC" => "QC1".to_string(),
+            _ if packet == "qfThreadInfo" => "m1".to_string(),
+            _ if packet == "qsThreadInfo" => "l".to_string(),
+            _ if packet == "qSymbol::" => "OK".to_string(),
+            _ => String::new(), // Not supported
+        };
+        (Some(reply), action)
+    }
+
+    // Z0/Z1 are breakpoints, Z2 write, Z3 read and Z4 access watchpoints. The kind field is the
+    // length of the watched area
+    fn handle_breakpoint(&mut self, packet: &str, emu: &mut Emulator) -> String {
+        let insert = packet.starts_with('Z');
+        let fields: Vec<&str> = packet[1..].split(',').collect();
+        let (Some(&kind), Some(address), Some(length)) = (fields.first(), fields.get(1).and_then(|field| parse_hex(field)), fields.get(2).and_then(|field| parse_hex(field))) else {
+            return "E01".to_string();
+        };
+        let (read, write) = match kind {
+            "0" | "1" => {
+                if insert {
+                    emu.breakpoints.insert(address);
+                } else {
+                    emu.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => (false, true),
+            "3" => (true, false),
+            "4" => (true, true),
+            _ => return String::new(),
+        };
+        // Watched areas have to be in memory, like the ranges of m and M packets
+        let length = length.max(1);
+        match address.checked_add(length) {
+            Some(end) if end <= emu.mem.size() => {}
+            Some(_) => return "E14".to_string(),
+            None => return "E01".to_string(),
+        }
+        let watchpoint = Watchpoint { start: address, length, read, write };
+        if insert {
+            emu.mem.watchpoints.push(watchpoint);
+        } else {
+            emu.mem.watchpoints.retain(|existing| *existing != watchpoint);
+        }
+        "OK".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(data: &str) -> Vec<u8> {
+        format!("${}#{:02x}", data, checksum(data.as_bytes())).into_bytes()
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"qSupported"), 0x37);
+        assert_eq!(checksum(&[0xFF, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn packets_are_taken_off_the_buffer() {
+        let mut buffer = b"+".to_vec();
+        buffer.extend(framed("g"));
+        buffer.push(0x03);
+        buffer.extend(b"$m200,4#00");
+        buffer.extend(b"$m200");
+        assert_eq!(take_packet(&mut buffer), Some(Received::Packet("g".to_string())));
+        assert_eq!(take_packet(&mut buffer), Some(Received::Interrupt));
+        assert_eq!(take_packet(&mut buffer), Some(Received::Corrupted));
+        // The last packet isn't all there yet and stays put
+        assert_eq!(take_packet(&mut buffer), None);
+        assert_eq!(buffer, b"$m200");
+        buffer.extend(b",4#");
+        assert_eq!(take_packet(&mut buffer), None);
+        buffer.extend(format!("{:02x}", checksum(b"m200,4")).bytes());
+        assert_eq!(take_packet(&mut buffer), Some(Received::Packet("m200,4".to_string())));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn memory_and_register_packets() {
+        let mut stub = GdbStub::listen(0).unwrap();
+        let mut emu = Emulator::new();
+        let mut reply = |packet: &str, emu: &mut Emulator| stub.handle_packet(packet, emu).0.unwrap();
+
+        assert_eq!(reply("M200,3:a1b2c3", &mut emu), "OK");
+        assert_eq!(reply("m200,3", &mut emu), "a1b2c3");
+        assert_eq!(reply("mfff,2", &mut emu), "E14");
+        assert_eq!(reply("Mfff,2:0000", &mut emu), "E14");
+        assert_eq!(reply("mffffffffffffffff,2", &mut emu), "E01");
+        assert_eq!(reply("Mffffffffffffffff,1:00", &mut emu), "E01");
+        assert_eq!(reply("m200", &mut emu), "E01");
+
+        assert_eq!(reply("P11=3402", &mut emu), "OK");
+        assert_eq!(emu.proc.program_counter, 0x234);
+        assert_eq!(reply("p11", &mut emu), "3402");
+        assert_eq!(reply("P3=7f", &mut emu), "OK");
+        assert_eq!(&reply("g", &mut emu)[..8], "0000007f");
+        assert_eq!(reply("p15", &mut emu), "E01");
+    }
+
+    #[test]
+    fn read_only_during_movies() {
+        let mut stub = GdbStub::listen(0).unwrap();
+        let mut emu = Emulator::new();
+        stub.read_only = true;
+        for packet in ["M200,1:ff", "P3=7f", "G00"] {
+            assert_eq!(stub.handle_packet(packet, &mut emu).0.as_deref(), Some("E01"));
+        }
+        assert_eq!(stub.handle_packet("m200,1", &mut emu).0.as_deref(), Some("00"));
+        stub.handle_packet("c300", &mut emu);
+        assert_eq!((emu.mem.peek(0x200), emu.proc.registers[3], emu.proc.program_counter), (0, 0, 0));
+    }
+
+    #[test]
+    fn breakpoints_and_resuming() {
+        let mut stub = GdbStub::listen(0).unwrap();
+        let mut emu = Emulator::new();
+        assert_eq!(stub.handle_packet("Z0,2a0,2", &mut emu).0.as_deref(), Some("OK"));
+        assert!(emu.breakpoints.contains(&0x2A0));
+        assert_eq!(stub.handle_packet("z0,2a0,2", &mut emu).0.as_deref(), Some("OK"));
+        assert!(emu.breakpoints.is_empty());
+        assert_eq!(stub.handle_packet("Z2,300,4", &mut emu).0.as_deref(), Some("OK"));
+        assert_eq!(emu.mem.watchpoints.len(), 1);
+        assert_eq!(stub.handle_packet("Z4,ffffffffffffffff,4", &mut emu).0.as_deref(), Some("E01"));
+        assert_eq!(stub.handle_packet("Z3,ffe,4", &mut emu).0.as_deref(), Some("E14"));
+        assert_eq!(emu.mem.watchpoints.len(), 1);
+        assert!(emu.mem.read_data(0xFFF).is_ok());
+
+        let (reply, action) = stub.handle_packet("c2a0", &mut emu);
+        assert!(reply.is_none() && matches!(action, Some(GdbAction::Continue)));
+        assert_eq!(emu.proc.program_counter, 0x2A0);
+        assert!(stub.waiting);
+        assert!(matches!(stub.handle_packet("s", &mut emu).1, Some(GdbAction::Step)));
+    }
+}
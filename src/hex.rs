@@ -0,0 +1,36 @@
+// Bytes as hex strings, two lowercase digits each, like save states and the GDB protocol use
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Either case is fine, anything that isn't pairs of hex digits isn't
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u32> = hex.chars().map(|c| c.to_digit(16)).collect::<Option<_>>()?;
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(digits.chunks(2).map(|pair| (pair[0] << 4 | pair[1]) as u8).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes = [0x00, 0x12, 0xAB, 0xFF];
+        assert_eq!(encode(&bytes), "0012abff");
+        assert_eq!(decode("0012abff"), Some(bytes.to_vec()));
+        assert_eq!(decode("0012ABFF"), Some(bytes.to_vec()));
+        assert_eq!(decode(""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_bad_digits() {
+        assert_eq!(decode("123"), None);
+        assert_eq!(decode("+f"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("é1"), None);
+    }
+}
@@ -1,24 +1,36 @@
-use macroquad::input::KeyCode;
+use macroquad::input::{is_key_down, KeyCode};
+
+// Physical keys for hex keys 0x0 to 0xF, in order
+const KEY_MAP: [KeyCode; 16] = [
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3,
+    KeyCode::Key4, KeyCode::Key5, KeyCode::Key6, KeyCode::Key7,
+    KeyCode::Key8, KeyCode::Key9, KeyCode::A, KeyCode::B,
+    KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F,
+];
+
+// Name of the physical key a hex key is on, e.g. 5 for Key5
+pub fn key_label(hex_key: usize) -> String {
+    let name = format!("{:?}", KEY_MAP[hex_key]);
+    name.strip_prefix("Key").unwrap_or(&name).to_string()
+}
 
 pub struct Keyboard {
-    pub last_key_pressed: KeyCode,
+    pub keys: u16, // One bit per hex key, bit 0 is key 0x0
 }
 
 impl Keyboard {
     pub fn new() -> Self {
         Keyboard {
-            last_key_pressed: KeyCode::Z,
+            keys: 0,
         }
     }
 
-    pub fn get_hexkey_pressed(&mut self) -> u8 {
-        let key = self.last_key_pressed as u16;
-        if key < 58 && key > 47 {
-            return (key - 48) as u8;
-        } else if key > 64 && key < 71 {
-            return (key - 55) as u8;
+    pub fn poll(&mut self) {
+        self.keys = 0;
+        for (hex_key, key) in KEY_MAP.iter().enumerate() {
+            if is_key_down(*key) {
+                self.keys |= 1 << hex_key;
+            }
         }
-        return 0xFF;
     }
 }
-
@@ -0,0 +1,97 @@
+use crate::keyboard::key_label;
+use macroquad::prelude::*;
+
+// Laid out like the COSMAC VIP's keypad
+const LAYOUT: [[usize; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+const IDLE_COLOR: Color = Color::new(0.2, 0.2, 0.2, 1.0);
+const POLLED_COLOR: Color = Color::new(0.85, 0.6, 0.1, 1.0);
+const HELD_COLOR: Color = Color::new(0.9, 0.9, 0.9, 1.0);
+
+// A hex keypad drawn to the right of the display that can be clicked or touched. Keys the
+// program checks are lit up, which shows what a game's controls are
+pub struct Keypad {
+    pub visible: bool,
+    pub polled: u16,  // Keys the program checked last frame
+    pub pressed: u16, // Keys held down with the mouse or a finger
+}
+
+impl Keypad {
+    pub fn new(visible: bool) -> Self {
+        Keypad {
+            visible,
+            polled: 0,
+            pressed: 0,
+        }
+    }
+
+    // Space taken from the right side of the window, the display gets the rest
+    pub fn width(&self) -> f32 {
+        if self.visible { (screen_width() * 0.3).min(screen_height()).floor() } else { 0.0 }
+    }
+
+    fn key_rects(&self) -> Vec<(usize, Rect)> {
+        let width = self.width();
+        let cell = width / 4.0;
+        let margin = (cell * 0.06).max(1.0);
+        let left = screen_width() - width;
+        let top = ((screen_height() - width) / 2.0).floor();
+        let mut rects = Vec::new();
+        for (row, keys) in LAYOUT.iter().enumerate() {
+            for (column, key) in keys.iter().enumerate() {
+                let rect = Rect::new(left + column as f32 * cell + margin, top + row as f32 * cell + margin, cell - 2.0 * margin, cell - 2.0 * margin);
+                rects.push((*key, rect));
+            }
+        }
+        rects
+    }
+
+    // Every finger counts, so several keys can be held at once on a touch screen
+    pub fn poll(&mut self) {
+        self.pressed = 0;
+        if !self.visible {
+            return;
+        }
+        let mut points: Vec<Vec2> = touches().iter().filter(|touch| !matches!(touch.phase, TouchPhase::Ended | TouchPhase::Cancelled)).map(|touch| touch.position).collect();
+        if is_mouse_button_down(MouseButton::Left) {
+            points.push(mouse_position().into());
+        }
+        for (key, rect) in self.key_rects() {
+            if points.iter().any(|point| rect.contains(*point)) {
+                self.pressed |= 1 << key;
+            }
+        }
+    }
+
+    // Each key shows its hex digit with the keyboard key it's on underneath
+    pub fn draw(&self, held: u16) {
+        if !self.visible {
+            return;
+        }
+        for (key, rect) in self.key_rects() {
+            let (color, text_color) = if (held >> key) & 1 == 1 {
+                (HELD_COLOR, BLACK)
+            } else if (self.polled >> key) & 1 == 1 {
+                (POLLED_COLOR, BLACK)
+            } else {
+                (IDLE_COLOR, WHITE)
+            };
+            draw_rectangle(rect.x, rect.y, rect.w, rect.h, color);
+
+            let digit = format!("{:X}", key);
+            let digit_size = (rect.h * 0.5) as u16;
+            let dimensions = measure_text(&digit, None, digit_size, 1.0);
+            draw_text(&digit, rect.x + (rect.w - dimensions.width) / 2.0, rect.y + rect.h * 0.55, digit_size as f32, text_color);
+
+            let label = format!("key {}", key_label(key));
+            let label_size = (rect.h * 0.18) as u16;
+            let dimensions = measure_text(&label, None, label_size, 1.0);
+            draw_text(&label, rect.x + (rect.w - dimensions.width) / 2.0, rect.y + rect.h * 0.85, label_size as f32, text_color);
+        }
+    }
+}
@@ -1,87 +1,412 @@
 mod processor;
+mod cheat;
 mod memory;
 mod emulator;
 mod renderer;
+mod display;
 mod keyboard;
+mod keypad;
+mod gamepad;
+mod config;
+mod movie;
+mod rng;
+mod palette;
+mod terminal;
+mod controller;
+mod watcher;
+mod platform;
+mod rom;
+mod patch;
+mod romdb;
+mod profiler;
+mod coverage;
+mod disassembler;
+mod analysis;
+mod gdb;
+mod hex;
+mod rpc;
+mod script;
 use std::env;
+use std::fs;
+use std::path::Path;
 use std::process;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread::spawn;
-use std::mem;
+use config::Config;
+use controller::{Command, Controller, Event, Status};
 use emulator::Emulator;
+use platform::Platform;
 use keyboard::Keyboard;
+use keypad::Keypad;
 use macroquad::prelude::*;
+use movie::{Movie, MoviePlayer, MovieRecorder};
 use renderer::Renderer;
+use romdb::{RomDatabase, RomInfo};
 
-#[macroquad::main("Chip-8 Emulator")]
-async fn main() {
+fn window_conf() -> Conf {
+    Conf {
+        window_title: "Chip-8 Emulator".to_string(),
+        window_width: 1280,
+        window_height: 640,
+        window_resizable: true,
+        ..Default::default()
+    }
+}
+
+fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <path to ROM>", args[0]);
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: {} {}", args[0], config::USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Some(cfg_path) = &config.cfg_path {
+        write_control_flow_graph(&config, cfg_path);
+    } else if config.terminal {
+        terminal::run(config);
+    } else {
+        macroquad::Window::from_config(window_conf(), window_main(config));
+    }
+}
+
+fn create_renderer(config: &Config) -> Renderer {
+    let mut renderer = Renderer::new();
+    if let Some(palette_path) = &config.palette_file {
+        match palette::load_palettes(palette_path) {
+            Ok(palettes) => renderer.add_palettes(palettes),
+            Err(e) => {
+                eprintln!("Failed to load palettes: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(palette_name) = &config.palette {
+        if !renderer.select_palette(palette_name) {
+            eprintln!("Unknown palette: {}", palette_name);
+            process::exit(1);
+        }
+    }
+    renderer.persistence = config.persistence;
+    renderer.decay_rate = config.decay_rate;
+    renderer.integer_scaling = config.integer_scaling;
+    renderer
+}
+
+// Loads the font and ROM, with the platform and memory layout worked out from the config, the
+// ROM database and the ROM itself. Returns what the ROM database knows about the ROM
+fn load_program(config: &Config, emu: &mut Emulator) -> RomInfo {
+    match emu.load_font("./font-data.bin") {
+        Ok(_) => println!("Font successfully loaded into Memory"),
+        Err(e) => {
+            eprintln!("Failed to load font-data.bin into Memory: {}", e);
+            process::exit(1);
+        }
+    }
+
+    println!("Loading ROM from: {}", config.rom_path);
+    let rom_data = match fs::read(&config.rom_path) {
+        Ok(rom_data) => rom_data,
+        Err(e) => {
+            eprintln!("Failed to read ROM: {}", e);
+            process::exit(1);
+        }
+    };
+    // The default database is optional, one asked for on the command line isn't
+    let rom_db_path = config.rom_db_path.clone().or_else(|| Path::new("./rom-db.txt").exists().then(|| "./rom-db.txt".to_string()));
+    let rom_db = match rom_db_path.map(|rom_db_path| RomDatabase::load(&rom_db_path)) {
+        Some(Ok(rom_db)) => rom_db,
+        Some(Err(e)) => {
+            eprintln!("Failed to load ROM database: {}", e);
+            process::exit(1);
+        }
+        None => RomDatabase::default(),
+    };
+    let rom_info = rom_db.lookup(&movie::hash_rom(&rom_data)).unwrap_or_default();
+
+    // Command line settings win over the database, which wins over guessing
+    let platform = match (config.platform.or(rom_info.platform), rom::detect_platform(&config.rom_path, &rom_data)) {
+        (Some(platform), _) => platform,
+        (None, Some((platform, reason))) => {
+            println!("Detected {} from {}", platform.name(), reason);
+            platform
+        }
+        (None, None) => Platform::Chip8,
+    };
+    emu.set_platform(platform);
+    if let Some(load_address) = config.load_address.or(rom_info.load_address) {
+        emu.memory_map.load_address = load_address;
+        emu.memory_map.entry_point = load_address;
+    }
+    if let Some(entry_point) = config.entry_point.or(rom_info.entry_point) {
+        emu.memory_map.entry_point = entry_point;
+    }
+    if let Some(stack_depth) = config.stack_depth.or(rom_info.stack_depth) {
+        emu.stack_depth = stack_depth;
+    }
+    emu.vip_stack = config.vip_stack;
+    if emu.vip_stack && emu.stack_depth > platform::VIP_STACK_SLOTS {
+        eprintln!("--vip-stack only has room for {} calls at 0xEA0-0xECF, but the stack depth is {}", platform::VIP_STACK_SLOTS, emu.stack_depth);
         process::exit(1);
     }
-    let renderer: Arc<Mutex<Renderer>> = Arc::new(Mutex::new(Renderer::new()));
-    let renderer_copy: Arc<Mutex<Renderer>> = Arc::clone(&renderer);
-    let keyboard_mutex: Arc<Mutex<Keyboard>> = Arc::new(Mutex::new(Keyboard::new()));
-    let keyboard_mutex_copy: Arc<Mutex<Keyboard>> = Arc::clone(&keyboard_mutex);
-    let thread_lock_mutex: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
-    let thread_lock_copy: Arc<Mutex<bool>> = Arc::clone(&thread_lock_mutex);
-    let mut emu: emulator::Emulator = emulator::Emulator::new(renderer_copy.clone(), keyboard_mutex_copy.clone(), thread_lock_copy.clone());
+    emu.memory_policy = config.memory_policy;
+    // Patches from the database go first, the command line ones can build on them
+    emu.patches = rom_info.patches.iter().chain(&config.patches).cloned().collect();
+    match emu.load_rom(&config.rom_path) {
+        Ok(warnings) => {
+            if !emu.patches.is_empty() {
+                println!("Applied {} patches to the ROM", emu.patches.len());
+            }
+            println!("ROM successfully loaded into Memory ({} bytes, {}, at {:03X})", emu.rom().len(), platform.name(), emu.memory_map.load_address);
+            for warning in warnings {
+                println!("Warning: {}", warning);
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to load ROM into Memory: {}", e);
+            process::exit(1);
+        }
+    }
+    rom_info
+}
 
-    let emulator_logic_thread = spawn(move || {
-        let rom_path = &args[1];
+// Loads the ROM without running it and writes its control flow graph
+fn write_control_flow_graph(config: &Config, cfg_path: &str) {
+    let mut emu = Emulator::new();
+    load_program(config, &mut emu);
+    let rom_start = emu.memory_map.load_address;
+    let graph = analysis::analyze(&emu.mem, emu.memory_map.entry_point, rom_start..rom_start + emu.rom().len());
+    for finding in &graph.findings {
+        println!("{:03X}: {}", finding.address, finding.message);
+    }
+    match fs::write(cfg_path, graph.to_dot(&emu.mem)) {
+        Ok(_) => println!("Wrote {} blocks in {} subroutines to {}", graph.blocks.len(), graph.subroutines.len(), cfg_path),
+        Err(e) => {
+            eprintln!("Failed to write control flow graph: {}", e);
+            process::exit(1);
+        }
+    }
+}
 
-        println!("Successfully created the CPU and Memory");
+// Loads the ROM and starts the emulator thread. Frontends talk to it through the controller
+pub fn create_controller(config: &Config) -> Controller {
+    let movie = config.replay_path.as_ref().map(|movie_path| {
+        match Movie::load(movie_path) {
+            Ok(movie) => movie,
+            Err(e) => {
+                eprintln!("Failed to load movie: {}", e);
+                process::exit(1);
+            }
+        }
+    });
+    // Replays need the same seed, RNG and speed the movie was recorded with
+    let seed = match (&movie, config.seed) {
+        (Some(movie), _) => movie.seed,
+        (None, Some(seed)) => seed,
+        (None, None) => ::rand::random(),
+    };
+    let rng_kind = movie.as_ref().map_or(config.rng, |movie| movie.rng);
+    let instructions_per_frame = movie.as_ref().map_or(config.instructions_per_frame, |movie| movie.instructions_per_frame);
+    let display_wait = movie.as_ref().map_or(config.display_wait, |movie| movie.display_wait);
+
+    let mut emu: Emulator = Emulator::new();
+    emu.display_wait = display_wait;
+    emu.set_rng(rng_kind.create(seed));
+    println!("Using {} RNG with seed {}", rng_kind.name(), seed);
+    emu.instructions_per_frame = instructions_per_frame;
+
+    // Saved flags would make a movie play out differently than it was recorded
+    if movie.is_none() && config.record_path.is_none() {
+        emu.flags_dir = Some(config.data_dir.clone());
+    }
 
-        println!("Loading ROM from: {}", rom_path);
-        match emu.mem.load_rom(rom_path) {
-            Ok(_) => println!("ROM successfully loaded into Memory"),
+    println!("Successfully created the CPU and Memory");
+
+    // Replays run on the machine the movie was recorded on, whatever the command line says
+    let mut config = config.clone();
+    if let Some(movie) = &movie {
+        config.platform = movie.platform.or(config.platform);
+        config.load_address = movie.load_address.or(config.load_address);
+        config.entry_point = movie.entry_point.or(config.entry_point);
+        config.stack_depth = movie.stack_depth.or(config.stack_depth);
+        config.vip_stack = movie.vip_stack.unwrap_or(config.vip_stack);
+        config.memory_policy = movie.memory_policy.unwrap_or(config.memory_policy);
+    }
+    let config = &config;
+    let rom_info = load_program(config, &mut emu);
+    emu.profiler = config.profile_path.as_deref().map(profiler::Profiler::new);
+    if let Some(script_path) = &config.script_path {
+        match script::Script::load(script_path) {
+            Ok(script) => emu.set_script(script),
             Err(e) => {
-                eprintln!("Failed to load ROM into Memory: {}", e);
+                eprintln!("Failed to load script {}: {}", script_path, e);
                 process::exit(1);
             }
         }
+    }
+    emu.mem.coverage = config.coverage_path.as_deref().map(|coverage_path| coverage::Coverage::new(coverage_path, emu.memory_map.size));
 
-        match emu.mem.load_rom_at_location("./font-data.bin", 0x0) {
-            Ok(_) => println!("ROM successfully loaded into Memory"),
+    let header = Movie {
+        rom_hash: movie::hash_rom(emu.rom()),
+        seed,
+        rng: rng_kind,
+        instructions_per_frame,
+        display_wait,
+        platform: Some(emu.platform),
+        load_address: Some(emu.memory_map.load_address),
+        entry_point: Some(emu.memory_map.entry_point),
+        stack_depth: Some(emu.stack_depth),
+        vip_stack: Some(emu.vip_stack),
+        memory_policy: Some(emu.memory_policy),
+        frames: Vec::new(),
+    };
+    let recorder = config.record_path.as_ref().map(|movie_path| {
+        match MovieRecorder::create(movie_path, &header) {
+            Ok(recorder) => recorder,
             Err(e) => {
-                eprintln!("Failed to load ROM into Memory: {}", e);
+                eprintln!("Failed to create movie file: {}", e);
                 process::exit(1);
             }
         }
+    });
+    let player = movie.map(|movie| {
+        // The hash is of the patched ROM, so a movie recorded with other patches is refused too
+        if movie.rom_hash != header.rom_hash {
+            eprintln!("Movie was recorded with a different ROM or patches ({}), the ROM loaded is {}", movie.rom_hash, header.rom_hash);
+            process::exit(1);
+        }
+        MoviePlayer::new(movie)
+    });
 
-        emu.prepare();
-        loop {
-            emu.clock();
+    let watcher = config.watch.then(|| watcher::FileWatcher::new(&config.rom_path));
+    let gdb = config.gdb_port.map(|port| match gdb::GdbStub::listen(port) {
+        Ok(gdb) => {
+            println!("Waiting for a debugger on port {}", port);
+            gdb
+        }
+        Err(e) => {
+            eprintln!("Failed to listen for a debugger on port {}: {}", port, e);
+            process::exit(1);
+        }
+    });
+    let rpc = config.rpc_port.map(|port| match rpc::RpcServer::listen(port) {
+        Ok(rpc) => {
+            println!("Listening for remote control on port {}", port);
+            rpc
+        }
+        Err(e) => {
+            eprintln!("Failed to listen for remote control on port {}: {}", port, e);
+            process::exit(1);
         }
     });
+    let gamepad = config.use_gamepad.then(|| gamepad::Gamepad::open(config.gamepad.or(rom_info.gamepad).unwrap_or_default()));
+    Controller::start(emu, player, recorder, watcher, gdb, rpc, gamepad, config.cheat_dir.clone())
+}
 
+async fn window_main(config: Config) {
+    let mut renderer = create_renderer(&config);
+    let mut keyboard = Keyboard::new();
+    let mut keypad = Keypad::new(config.keypad);
+    let mut controller = create_controller(&config);
+    let mut status = Status {
+        paused: false,
+        halted: false,
+        speed: controller::Speed::Normal,
+        instructions_per_frame: config.instructions_per_frame,
+    };
 
+    let mut fullscreen = config.fullscreen;
+    if fullscreen {
+        set_fullscreen(true);
+    }
+    // Closing the window shuts the emulator thread down first
+    prevent_quit();
     loop {
-        let mut keyboard = keyboard_mutex.lock().unwrap(); 
-
-        if let Some(key) = get_last_key_pressed() {
-            (*keyboard).last_key_pressed = key;
-            if key == KeyCode::Enter {
-                let mut thread_lock = thread_lock_mutex.lock().unwrap();
-                *thread_lock = false;
-                mem::drop(thread_lock);
-            } 
-        } else {
-            if is_key_released((*keyboard).last_key_pressed) {
-                (*keyboard).last_key_pressed = KeyCode::Z;
+        let previous_keys = keyboard.keys | keypad.pressed;
+        keyboard.poll();
+        keypad.poll();
+        let keys = keyboard.keys | keypad.pressed;
+        if keys != previous_keys {
+            controller.send(Command::SetKeys(keys));
+        }
+        if is_key_pressed(KeyCode::Space) {
+            controller.send(if status.paused { Command::Resume } else { Command::Pause });
+        }
+        if is_key_pressed(KeyCode::Enter) && status.paused {
+            controller.send(Command::Step);
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            controller.send(Command::Reset);
+        }
+        if is_key_pressed(KeyCode::N) && status.paused {
+            controller.send(Command::AdvanceFrame);
+        }
+        if is_key_pressed(KeyCode::RightBracket) {
+            controller.send(Command::SetSpeed(status.speed.next_fast_forward()));
+        }
+        if is_key_pressed(KeyCode::LeftBracket) {
+            controller.send(Command::SetSpeed(status.speed.next_slow_motion()));
+        }
+        // Small steps at low speeds, roughly 10% at high ones
+        let ipf_step = (status.instructions_per_frame / 10).max(1);
+        if is_key_pressed(KeyCode::Equal) {
+            controller.send(Command::SetInstructionsPerFrame(status.instructions_per_frame + ipf_step));
+        }
+        if is_key_pressed(KeyCode::Minus) {
+            controller.send(Command::SetInstructionsPerFrame(status.instructions_per_frame.saturating_sub(ipf_step)));
+        }
+        if is_key_pressed(KeyCode::K) {
+            controller.send(Command::InspectCallStack);
+        }
+        if is_key_pressed(KeyCode::Tab) {
+            keypad.visible = !keypad.visible;
+        }
+        if is_key_pressed(KeyCode::H) {
+            renderer.show_hud = !renderer.show_hud;
+        }
+        if is_key_pressed(KeyCode::P) {
+            println!("Switched to {} palette", renderer.next_palette());
+        }
+        if is_key_pressed(KeyCode::L) {
+            println!("Persistence: {}", renderer.next_persistence().name());
+        }
+        if is_key_pressed(KeyCode::F11) {
+            fullscreen = !fullscreen;
+            set_fullscreen(fullscreen);
+        }
+        if is_key_pressed(KeyCode::I) {
+            renderer.integer_scaling = !renderer.integer_scaling;
+        }
+
+        for event in controller.events() {
+            match event {
+                Event::FrameReady(display) => renderer.present(&display),
+                Event::StatusChanged(new_status) => status = new_status,
+                Event::BeepOn | Event::BeepOff => {} // No audio output yet
+                Event::Halted(reason) => eprintln!("Emulator halted: {}", reason),
+                Event::Break(reason) => eprintln!("Break: {}", reason),
+                Event::CallStack(frames) => {
+                    println!("Call stack ({} deep):", frames.len());
+                    for frame in frames.iter().rev() {
+                        println!("  called from {:03X}, returns to {:03X}", frame.call_site, frame.return_address);
+                    }
+                }
+                Event::PolledKeys(keys) => keypad.polled = keys,
+                Event::Error(message) => eprintln!("{}", message),
+                Event::Message(message) => println!("{}", message),
             }
         }
-        mem::drop(keyboard);
-        let mut rend = renderer.lock().unwrap();
-        (*rend).do_render();
-        mem::drop(rend);
+        renderer.reserved_width = keypad.width();
+        renderer.do_render();
+        keypad.draw(keyboard.keys | keypad.pressed);
+        renderer.draw_hud(&status);
+
+        if is_quit_requested() {
+            controller.shutdown();
+            break;
+        }
         next_frame().await
     }
 }
-
-fn set_last_key_pressed(emu: &mut Emulator) {
-}
-
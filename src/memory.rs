@@ -1,53 +1,238 @@
-use std::fs::File;
-use std::io::{self, Read};
+use crate::coverage::Coverage;
+use std::cell::Cell;
+use std::fmt;
+
+// What happens when a program accesses an address past the end of memory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MemoryPolicy {
+    Wrap,  // Address modulo memory size, like the address lines of real hardware
+    Fault, // Stop the program with an error
+    Break, // Pause the program so it can be inspected
+}
+
+impl MemoryPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wrap" => Some(MemoryPolicy::Wrap),
+            "fault" => Some(MemoryPolicy::Fault),
+            "break" => Some(MemoryPolicy::Break),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryPolicy::Wrap => "wrap",
+            MemoryPolicy::Fault => "fault",
+            MemoryPolicy::Break => "break",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AccessKind {
+    Fetch,
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy)]
+pub struct MemoryFault {
+    pub address: usize,
+    pub kind: AccessKind,
+    pub debug_break: bool, // Raised under MemoryPolicy::Break
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::Fetch => "Instruction fetch from",
+            AccessKind::Read => "Read from",
+            AccessKind::Write => "Write to",
+        };
+        write!(f, "{} {:04X}, past the end of memory", kind, self.address)
+    }
+}
+
+// Stops the program after an instruction reads or writes any of the watched bytes
+#[derive(Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub length: usize,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, address: usize, kind: AccessKind) -> bool {
+        let watched = match kind {
+            AccessKind::Fetch => false,
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+        };
+        watched && address.checked_sub(self.start).is_some_and(|offset| offset < self.length)
+    }
+}
 
 pub struct Memory {
-    data: [u8; 0x1000], // The computers running CHIP-8 had 4096 (0x1000) bytes of ram
+    data: Vec<u8>, // The computers running CHIP-8 had 4096 (0x1000) bytes of ram, XO-CHIP has 64K
+    policy: MemoryPolicy,
+    pub coverage: Option<Coverage>,
+    pub watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<(usize, AccessKind)>>, // First watched access since the last take_watch_hit
+    pub record_writes: bool, // Keep the writes around for scripts
+    writes: Vec<(usize, u8)>, // Since the last take_writes
 }
 
 impl Memory {
-    pub fn new() -> Self {
-        Memory { data: [0; 0x1000] } // Initializes all bytes to 0
+    pub fn new(size: usize, policy: MemoryPolicy) -> Self {
+        Memory {
+            data: vec![0; size], // Initializes all bytes to 0
+            policy,
+            coverage: None,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            record_writes: false,
+            writes: Vec::new(),
+        }
     }
 
-    pub fn load_rom(&mut self, file_path: &str) -> io::Result<()> {
-        self.load_rom_at_location(file_path, 0x200)
+    pub fn size(&self) -> usize {
+        self.data.len()
     }
-    
-    pub fn load_rom_at_location(&mut self, file_path: &str, start_address: usize) -> io::Result<()> {
-        let mut file = File::open(file_path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
 
+    pub fn load_at_location(&mut self, buffer: &[u8], start_address: usize) {
         let end_address = start_address + buffer.len();
-        self.data[start_address..end_address].copy_from_slice(&buffer);
-        Ok(())
+        self.data[start_address..end_address].copy_from_slice(buffer);
+    }
 
+    // Every access goes through here so the policy applies the same way everywhere
+    fn resolve(&self, address: usize, kind: AccessKind) -> Result<usize, MemoryFault> {
+        let address = if address < self.data.len() {
+            address
+        } else {
+            match self.policy {
+                MemoryPolicy::Wrap => address % self.data.len(),
+                MemoryPolicy::Fault | MemoryPolicy::Break => {
+                    return Err(MemoryFault {
+                        address,
+                        kind,
+                        debug_break: self.policy == MemoryPolicy::Break,
+                    })
+                }
+            }
+        };
+        if let Some(coverage) = &self.coverage {
+            coverage.record(address, kind);
+        }
+        if self.watch_hit.get().is_none() && self.watchpoints.iter().any(|watchpoint| watchpoint.matches(address, kind)) {
+            self.watch_hit.set(Some((address, kind)));
+        }
+        Ok(address)
+    }
+
+    // Reads without counting as an access, for tools looking at memory and the interpreter's
+    // own reads. Out of range reads give 0
+    pub fn peek(&self, address: usize) -> u8 {
+        self.data.get(address).copied().unwrap_or(0)
     }
 
-    pub fn read_data(&self, address: usize) -> u8 {
-        self.data[address]
+    pub fn take_watch_hit(&self) -> Option<(usize, AccessKind)> {
+        self.watch_hit.take()
     }
 
-    pub fn write_data(&mut self, address: usize, data: u8) {
+    pub fn take_writes(&mut self) -> Vec<(usize, u8)> {
+        std::mem::take(&mut self.writes)
+    }
+
+    // Writes without counting as an access, for debuggers. Returns false when out of range
+    pub fn poke(&mut self, address: usize, value: u8) -> bool {
+        match self.data.get_mut(address) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn read_data(&self, address: usize) -> Result<u8, MemoryFault> {
+        Ok(self.data[self.resolve(address, AccessKind::Read)?])
+    }
+
+    pub fn write_data(&mut self, address: usize, data: u8) -> Result<(), MemoryFault> {
+        let address = self.resolve(address, AccessKind::Write)?;
         self.data[address] = data;
+        if self.record_writes {
+            self.writes.push((address, data));
+        }
+        Ok(())
     }
 
+    #[allow(dead_code)] // Debugging helper
     pub fn print_mem(&self) {
         for (i, val) in self.data.iter().enumerate() {
             print!("{:02X} ", val);
             if i > 0 && i % 128 == 0 {
-                println!("");
+                println!();
             }
         }
     }
     
-    pub fn read_instruction(&self, address: usize) -> u16 { // Instructions on the CHIP-8 are
-                                                            // 16bits long, also it's in big endian
-                                                            // format so we put the low byte after
-                                                            // high byte
-        let high = self.data[address];
-        let low = self.data[address + 1];
-        ((high as u16) << 8) | (low as u16)
+    pub fn read_instruction(&self, address: usize) -> Result<u16, MemoryFault> { // Instructions on the CHIP-8 are
+                                                                                   // 16bits long, also it's in big endian
+                                                                                   // format so we put the low byte after
+                                                                                   // high byte
+        let high = self.data[self.resolve(address, AccessKind::Fetch)?];
+        let low = self.data[self.resolve(address.wrapping_add(1), AccessKind::Fetch)?];
+        Ok(((high as u16) << 8) | (low as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(result: Result<impl Sized, MemoryFault>) -> Option<(usize, AccessKind, bool)> {
+        result.err().map(|fault| (fault.address, fault.kind, fault.debug_break))
+    }
+
+    #[test]
+    fn wrap() {
+        let mut mem = Memory::new(16, MemoryPolicy::Wrap);
+        assert!(mem.write_data(18, 0x12).is_ok());
+        assert_eq!(mem.peek(2), 0x12);
+        assert_eq!(mem.read_data(34).ok(), Some(0x12));
+        mem.poke(15, 0xAB);
+        mem.poke(0, 0xCD);
+        assert_eq!(mem.read_instruction(15).ok(), Some(0xABCD));
+        assert_eq!(mem.read_data(usize::MAX).ok(), Some(0xAB));
+        assert_eq!(mem.read_instruction(usize::MAX).ok(), Some(0xABCD));
+    }
+
+    #[test]
+    fn fault_policy() {
+        let mut mem = Memory::new(16, MemoryPolicy::Fault);
+        assert_eq!(fault(mem.read_data(16)), Some((16, AccessKind::Read, false)));
+        assert_eq!(fault(mem.write_data(usize::MAX, 1)), Some((usize::MAX, AccessKind::Write, false)));
+        assert_eq!(fault(mem.read_instruction(15)), Some((16, AccessKind::Fetch, false)));
+        assert!(mem.write_data(15, 1).is_ok());
+        assert_eq!(mem.read_data(16).err().unwrap().to_string(), "Read from 0010, past the end of memory");
+    }
+
+    #[test]
+    fn break_policy() {
+        let mut mem = Memory::new(16, MemoryPolicy::Break);
+        assert_eq!(fault(mem.read_data(20)), Some((20, AccessKind::Read, true)));
+        assert_eq!(fault(mem.write_data(16, 1)), Some((16, AccessKind::Write, true)));
+        assert_eq!(fault(mem.read_instruction(16)), Some((16, AccessKind::Fetch, true)));
+    }
+
+    #[test]
+    fn policy_names() {
+        for policy in [MemoryPolicy::Wrap, MemoryPolicy::Fault, MemoryPolicy::Break] {
+            assert_eq!(MemoryPolicy::from_name(policy.name()), Some(policy));
+        }
+        assert!(MemoryPolicy::from_name("ignore").is_none());
     }
 }
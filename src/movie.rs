@@ -0,0 +1,286 @@
+use crate::memory::MemoryPolicy;
+use crate::platform::Platform;
+use crate::rng::*;
+use crate::romdb::{parse_address, parse_stack_depth};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+const MOVIE_MAGIC: &str = "chip8-movie 1";
+
+// A movie is everything needed to reproduce a session: the ROM it was recorded against, the
+// emulator configuration and the keypad state of every frame. The machine settings are missing
+// from movies recorded before they were saved, those play with whatever is configured
+pub struct Movie {
+    pub rom_hash: String,
+    pub seed: u64,
+    pub rng: RngKind,
+    pub instructions_per_frame: u32,
+    pub display_wait: bool,
+    pub platform: Option<Platform>,
+    pub load_address: Option<usize>,
+    pub entry_point: Option<usize>,
+    pub stack_depth: Option<usize>,
+    pub vip_stack: Option<bool>,
+    pub memory_policy: Option<MemoryPolicy>,
+    pub frames: Vec<MovieFrame>,
+}
+
+#[derive(Clone, Copy)]
+pub struct MovieFrame {
+    pub keys: u16,
+    pub reset: bool, // The machine was reset right before this frame
+    pub instructions_per_frame: Option<u32>, // Speed was changed right before this frame
+}
+
+pub fn hash_rom(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// At least one instruction has to run per frame, or nothing ever happens
+fn parse_instructions_per_frame(value: &str) -> io::Result<u32> {
+    value.parse().ok().filter(|instructions_per_frame| *instructions_per_frame > 0).ok_or_else(|| invalid_data(format!("Invalid ipf: {}", value)))
+}
+
+impl Movie {
+    pub fn load(file_path: &str) -> io::Result<Self> {
+        Movie::read(BufReader::new(File::open(file_path)?)).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => invalid_data(format!("{}: {}", file_path, e)),
+            _ => e,
+        })
+    }
+
+    fn read(reader: impl BufRead) -> io::Result<Self> {
+        let mut lines = reader.lines();
+        if lines.next().transpose()?.as_deref() != Some(MOVIE_MAGIC) {
+            return Err(invalid_data("not a movie file".to_string()));
+        }
+
+        let mut movie = Movie {
+            rom_hash: String::new(),
+            seed: 0,
+            rng: RngKind::Seeded,
+            instructions_per_frame: 0,
+            display_wait: false,
+            platform: None,
+            load_address: None,
+            entry_point: None,
+            stack_depth: None,
+            vip_stack: None,
+            memory_policy: None,
+            frames: Vec::new(),
+        };
+
+        // Header is "key value" pairs, terminated by the "frames" line. Without the ROM, seed
+        // and speed there's nothing to replay
+        let (mut rom_hash, mut seed, mut instructions_per_frame) = (None, None, None);
+        for line in lines.by_ref() {
+            let line = line?;
+            let (key, value) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+            match key {
+                "rom" => rom_hash = Some(value.to_string()),
+                "seed" => seed = Some(value.parse().map_err(|_| invalid_data(format!("Invalid seed: {}", value)))?),
+                "rng" => movie.rng = RngKind::from_name(value).ok_or_else(|| invalid_data(format!("Unknown RNG: {}", value)))?,
+                "ipf" => instructions_per_frame = Some(parse_instructions_per_frame(value)?),
+                "display-wait" => movie.display_wait = value == "1",
+                "platform" => movie.platform = Some(Platform::from_name(value).ok_or_else(|| invalid_data(format!("Unknown platform: {}", value)))?),
+                "load-address" => movie.load_address = Some(parse_address(value).ok_or_else(|| invalid_data(format!("Invalid load address: {}", value)))?),
+                "entry-point" => movie.entry_point = Some(parse_address(value).ok_or_else(|| invalid_data(format!("Invalid entry point: {}", value)))?),
+                "stack-depth" => movie.stack_depth = Some(parse_stack_depth(value).ok_or_else(|| invalid_data(format!("Invalid stack depth: {}", value)))?),
+                "vip-stack" => movie.vip_stack = Some(value == "1"),
+                "memory-policy" => movie.memory_policy = Some(MemoryPolicy::from_name(value).ok_or_else(|| invalid_data(format!("Unknown memory policy: {}", value)))?),
+                "frames" => break,
+                _ => return Err(invalid_data(format!("Unknown movie header field: {}", key))),
+            }
+        }
+        movie.rom_hash = rom_hash.ok_or_else(|| invalid_data("Movie header is missing the rom".to_string()))?;
+        movie.seed = seed.ok_or_else(|| invalid_data("Movie header is missing the seed".to_string()))?;
+        movie.instructions_per_frame = instructions_per_frame.ok_or_else(|| invalid_data("Movie header is missing the ipf".to_string()))?;
+
+        // One line of keys per frame, optionally preceded by "reset" and "ipf <n>" lines
+        let mut reset = false;
+        let mut instructions_per_frame = None;
+        for line in lines {
+            let line = line?;
+            let line = line.trim();
+            if line == "reset" {
+                reset = true;
+                continue;
+            }
+            if let Some(value) = line.strip_prefix("ipf ") {
+                instructions_per_frame = Some(parse_instructions_per_frame(value)?);
+                continue;
+            }
+            let keys = u16::from_str_radix(line, 16).map_err(|_| invalid_data(format!("Invalid frame: {}", line)))?;
+            movie.frames.push(MovieFrame { keys, reset, instructions_per_frame });
+            reset = false;
+            instructions_per_frame = None;
+        }
+        Ok(movie)
+    }
+}
+
+// Writes the movie as it's being played so nothing is lost if the window is closed
+pub struct MovieRecorder {
+    writer: BufWriter<File>,
+}
+
+impl MovieRecorder {
+    // Writes the header from the movie, its frames are left out
+    pub fn create(file_path: &str, header: &Movie) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(file_path)?);
+        writeln!(writer, "{}", MOVIE_MAGIC)?;
+        writeln!(writer, "rom {}", header.rom_hash)?;
+        writeln!(writer, "seed {}", header.seed)?;
+        writeln!(writer, "rng {}", header.rng.name())?;
+        writeln!(writer, "ipf {}", header.instructions_per_frame)?;
+        writeln!(writer, "display-wait {}", header.display_wait as u8)?;
+        if let Some(platform) = header.platform {
+            writeln!(writer, "platform {}", platform.id())?;
+        }
+        if let Some(load_address) = header.load_address {
+            writeln!(writer, "load-address {:03X}", load_address)?;
+        }
+        if let Some(entry_point) = header.entry_point {
+            writeln!(writer, "entry-point {:03X}", entry_point)?;
+        }
+        if let Some(stack_depth) = header.stack_depth {
+            writeln!(writer, "stack-depth {}", stack_depth)?;
+        }
+        if let Some(vip_stack) = header.vip_stack {
+            writeln!(writer, "vip-stack {}", vip_stack as u8)?;
+        }
+        if let Some(memory_policy) = header.memory_policy {
+            writeln!(writer, "memory-policy {}", memory_policy.name())?;
+        }
+        writeln!(writer, "frames")?;
+        writer.flush()?;
+        Ok(MovieRecorder { writer })
+    }
+
+    pub fn record_frame(&mut self, frame: MovieFrame) -> io::Result<()> {
+        if frame.reset {
+            writeln!(self.writer, "reset")?;
+        }
+        if let Some(instructions_per_frame) = frame.instructions_per_frame {
+            writeln!(self.writer, "ipf {}", instructions_per_frame)?;
+        }
+        writeln!(self.writer, "{:04X}", frame.keys)?;
+        self.writer.flush()
+    }
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        MoviePlayer { movie, frame: 0 }
+    }
+
+    // Returns None once every recorded frame has been played back
+    pub fn next_frame(&mut self) -> Option<MovieFrame> {
+        let frame = self.movie.frames.get(self.frame).copied();
+        self.frame += 1;
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Movie {
+        Movie {
+            rom_hash: hash_rom(b"rom"),
+            seed: 12345,
+            rng: RngKind::Vip,
+            instructions_per_frame: 15,
+            display_wait: true,
+            platform: Some(Platform::Eti660),
+            load_address: Some(0x600),
+            entry_point: Some(0x602),
+            stack_depth: Some(20),
+            vip_stack: Some(true),
+            memory_policy: Some(MemoryPolicy::Wrap),
+            frames: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn recorded_movies_load_back() {
+        let path = std::env::temp_dir().join(format!("chip8-movie-test-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut recorder = MovieRecorder::create(path, &header()).unwrap();
+        recorder.record_frame(MovieFrame { keys: 0x0010, reset: false, instructions_per_frame: None }).unwrap();
+        recorder.record_frame(MovieFrame { keys: 0x8001, reset: true, instructions_per_frame: Some(30) }).unwrap();
+        drop(recorder);
+        let movie = Movie::load(path);
+        std::fs::remove_file(path).unwrap();
+
+        let movie = movie.unwrap();
+        let expected = header();
+        assert_eq!(movie.rom_hash, expected.rom_hash);
+        assert_eq!((movie.seed, movie.instructions_per_frame, movie.display_wait), (12345, 15, true));
+        assert!(movie.rng == RngKind::Vip);
+        assert_eq!(movie.platform, Some(Platform::Eti660));
+        assert_eq!((movie.load_address, movie.entry_point, movie.stack_depth, movie.vip_stack), (Some(0x600), Some(0x602), Some(20), Some(true)));
+        assert!(movie.memory_policy == Some(MemoryPolicy::Wrap));
+        assert_eq!(movie.frames.len(), 2);
+        assert_eq!((movie.frames[0].keys, movie.frames[0].reset, movie.frames[0].instructions_per_frame), (0x0010, false, None));
+        assert_eq!((movie.frames[1].keys, movie.frames[1].reset, movie.frames[1].instructions_per_frame), (0x8001, true, Some(30)));
+    }
+
+    #[test]
+    fn older_movies_leave_the_machine_alone() {
+        let text = "chip8-movie 1\nrom abc\nseed 7\nrng seeded\nipf 10\ndisplay-wait 0\nframes\n0000\n";
+        let movie = Movie::read(text.as_bytes()).unwrap();
+        assert_eq!(movie.seed, 7);
+        assert_eq!(movie.platform, None);
+        assert_eq!((movie.load_address, movie.stack_depth, movie.vip_stack), (None, None, None));
+        assert!(movie.memory_policy.is_none());
+        assert_eq!(movie.frames.len(), 1);
+    }
+
+    #[test]
+    fn frames_play_back_in_order() {
+        let mut movie = header();
+        movie.frames = vec![
+            MovieFrame { keys: 0x0001, reset: false, instructions_per_frame: None },
+            MovieFrame { keys: 0x0002, reset: true, instructions_per_frame: None },
+        ];
+        let mut player = MoviePlayer::new(movie);
+        assert_eq!(player.next_frame().map(|frame| frame.keys), Some(0x0001));
+        assert_eq!(player.next_frame().map(|frame| (frame.keys, frame.reset)), Some((0x0002, true)));
+        assert!(player.next_frame().is_none());
+        assert!(player.next_frame().is_none());
+    }
+
+    #[test]
+    fn roms_are_told_apart_by_hash() {
+        assert_eq!(hash_rom(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_ne!(hash_rom(&[0x00, 0xE0]), hash_rom(&[0x00, 0xE1]));
+    }
+
+    #[test]
+    fn bad_movies_are_refused() {
+        assert!(Movie::read("not a movie\n".as_bytes()).is_err());
+        assert!(Movie::read("chip8-movie 1\nspeed 3\nframes\n".as_bytes()).is_err());
+        // The ROM, seed and speed have to be there
+        let header = ["rom abc", "seed 7", "ipf 10"];
+        for missing in 0..header.len() {
+            let fields: Vec<&str> = header.iter().enumerate().filter(|(i, _)| *i != missing).map(|(_, field)| *field).collect();
+            let text = format!("chip8-movie 1\n{}\nframes\n0000\n", fields.join("\n"));
+            assert_eq!(Movie::read(text.as_bytes()).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        }
+        assert!(Movie::read("chip8-movie 1\nrom abc\nseed 7\nipf 0\nframes\n".as_bytes()).is_err());
+        assert!(Movie::read("chip8-movie 1\nrom abc\nseed 7\nipf 10\nframes\nipf 0\n0000\n".as_bytes()).is_err());
+        assert!(Movie::read("chip8-movie 1\nstack-depth 0\nframes\n".as_bytes()).is_err());
+        assert!(Movie::read("chip8-movie 1\nframes\nzz\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs;
+use std::io;
+use macroquad::color::Color;
+
+// colors[0] is the background and colors[1] the foreground. XO-CHIP draws on two planes, so
+// colors[2] is used for pixels only set on the second plane and colors[3] where both overlap
+#[derive(Clone)]
+pub struct Palette {
+    pub name: String,
+    pub colors: [Color; 4],
+}
+
+fn rgb(value: u32) -> Color {
+    Color::from_rgba((value >> 16) as u8, (value >> 8) as u8, value as u8, 255)
+}
+
+fn palette(name: &str, colors: [u32; 4]) -> Palette {
+    Palette {
+        name: name.to_string(),
+        colors: colors.map(rgb),
+    }
+}
+
+pub fn builtin_palettes() -> Vec<Palette> {
+    vec![
+        palette("classic", [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555]),
+        palette("green", [0x041204, 0x33FF66, 0x1C9938, 0x0E4C1C]),
+        palette("amber", [0x140C00, 0xFFB000, 0xB37B00, 0x5C3F00]),
+        palette("hp48", [0x8C9A84, 0x252B22, 0x4A5444, 0x6B7765]),
+        palette("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),
+    ]
+}
+
+fn parse_color(text: &str) -> Option<Color> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok().map(rgb)
+}
+
+// Each line is "<name> <background> <foreground> [<plane 2> <plane 3>]" with colors written
+// as #RRGGBB, lines starting with # are comments
+pub fn load_palettes(file_path: &str) -> io::Result<Vec<Palette>> {
+    let mut palettes = Vec::new();
+    for (line_number, line) in fs::read_to_string(file_path)?.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue;
+        }
+
+        let colors: Option<Vec<Color>> = fields[1..].iter().map(|field| parse_color(field)).collect();
+        let palette = match colors.as_deref() {
+            Some([background, foreground]) => [*background, *foreground, *foreground, *foreground],
+            Some([background, foreground, plane_2, plane_3]) => [*background, *foreground, *plane_2, *plane_3],
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: expected a name followed by 2 or 4 colors", file_path, line_number + 1)));
+            }
+        };
+        palettes.push(Palette {
+            name: fields[0].to_string(),
+            colors: palette,
+        });
+    }
+    Ok(palettes)
+}
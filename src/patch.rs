@@ -0,0 +1,274 @@
+use crate::hex;
+use crate::romdb::parse_address;
+use std::fs;
+
+// Nothing larger fits in memory on any platform, so patches can't grow a ROM past it
+const MAX_ROM_SIZE: usize = 0x10000;
+
+// Makes room for bytes written at offset..end, refusing to grow past what any platform can load
+fn grow(target: &mut Vec<u8>, offset: usize, length: usize) -> Result<usize, String> {
+    let end = offset.checked_add(length).filter(|end| *end <= MAX_ROM_SIZE).ok_or_else(|| format!("patch writes past {:X} bytes", MAX_ROM_SIZE))?;
+    if target.len() < end {
+        target.resize(end, 0);
+    }
+    Ok(end)
+}
+
+// Changes made to a ROM as it's loaded, so broken ROMs can be fixed without touching the file
+#[derive(Clone)]
+pub enum Patch {
+    File(String), // IPS or BPS, told apart by their header
+    Bytes { address: usize, bytes: Vec<u8> },
+}
+
+impl Patch {
+    // Either `<address>=<hex bytes>` or the path of a patch file
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let Some((address, bytes)) = value.split_once('=') else {
+            return Ok(Patch::File(value.to_string()));
+        };
+        let address = parse_address(address).ok_or_else(|| format!("Invalid patch address: {}", address))?;
+        let digits: String = bytes.chars().filter(|c| !c.is_whitespace()).collect();
+        let bytes = hex::decode(&digits).filter(|bytes| !bytes.is_empty()).ok_or_else(|| format!("Invalid patch bytes: {}", digits))?;
+        Ok(Patch::Bytes { address, bytes })
+    }
+
+    // Byte patches are given as memory addresses, so they need to know where the ROM goes
+    pub fn apply(&self, rom: &mut Vec<u8>, load_address: usize) -> Result<(), String> {
+        match self {
+            Patch::Bytes { address, bytes } => {
+                let offset = address.checked_sub(load_address).ok_or_else(|| format!("Patch at {:03X} is before the ROM at {:03X}", address, load_address))?;
+                let end = grow(rom, offset, bytes.len()).map_err(|_| format!("Patch at {:03X} is past the end of memory", address))?;
+                rom[offset..end].copy_from_slice(bytes);
+                Ok(())
+            }
+            Patch::File(file_path) => {
+                let data = fs::read(file_path).map_err(|e| format!("Failed to read {}: {}", file_path, e))?;
+                let patched = if data.starts_with(b"PATCH") {
+                    apply_ips(rom, &data)
+                } else if data.starts_with(b"BPS1") {
+                    apply_bps(rom, &data)
+                } else {
+                    Err("not an IPS or BPS patch".to_string())
+                };
+                *rom = patched.map_err(|e| format!("{}: {}", file_path, e))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Records of a 3 byte offset and 2 byte size followed by the bytes, or by a 2 byte count and a
+// byte to repeat when the size is 0. Ends with EOF, optionally followed by a length to truncate to
+fn apply_ips(rom: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let truncated = || "patch is truncated".to_string();
+    let mut target = rom.to_vec();
+    let mut position = 5;
+    loop {
+        let read = |position: usize, length: usize| data.get(position..position + length).ok_or_else(truncated);
+        if read(position, 3)? == b"EOF" {
+            if let Ok(length) = read(position + 3, 3) {
+                target.truncate(((length[0] as usize) << 16) | ((length[1] as usize) << 8) | length[2] as usize);
+            }
+            return Ok(target);
+        }
+        let record = read(position, 5)?;
+        let offset = ((record[0] as usize) << 16) | ((record[1] as usize) << 8) | record[2] as usize;
+        let size = ((record[3] as usize) << 8) | record[4] as usize;
+        position += 5;
+        let bytes = if size == 0 {
+            let run = read(position, 3)?;
+            position += 3;
+            vec![run[2]; ((run[0] as usize) << 8) | run[1] as usize]
+        } else {
+            position += size;
+            read(position - size, size)?.to_vec()
+        };
+        let end = grow(&mut target, offset, bytes.len())?;
+        target[offset..end].copy_from_slice(&bytes);
+    }
+}
+
+// Numbers are variable length, 7 bits a byte with the top bit marking the last one
+fn bps_number(data: &[u8], position: &mut usize) -> Result<usize, String> {
+    let mut number = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let byte = *data.get(*position).ok_or("patch is truncated")?;
+        *position += 1;
+        number = number.checked_add((byte as usize & 0x7F).checked_mul(shift).ok_or("number too large")?).ok_or("number too large")?;
+        if byte & 0x80 != 0 {
+            return Ok(number);
+        }
+        shift = shift.checked_shl(7).ok_or("number too large")?;
+        number = number.checked_add(shift).ok_or("number too large")?;
+    }
+}
+
+// Builds the target out of copies from the source, the patch and what was already written.
+// The checksums at the end make sure the patch is for this ROM
+fn apply_bps(rom: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 4 + 12 {
+        return Err("patch is truncated".to_string());
+    }
+    let footer = &data[data.len() - 12..];
+    let checksum = |index: usize| u32::from_le_bytes(footer[index * 4..index * 4 + 4].try_into().unwrap());
+    if crc32(&data[..data.len() - 4]) != checksum(2) {
+        return Err("patch is corrupted".to_string());
+    }
+
+    let actions = &data[..data.len() - 12];
+    let mut position = 4;
+    let source_size = bps_number(actions, &mut position)?;
+    let target_size = bps_number(actions, &mut position)?;
+    let metadata_size = bps_number(actions, &mut position)?;
+    position = position.checked_add(metadata_size).ok_or("patch is truncated")?;
+    if target_size > MAX_ROM_SIZE {
+        return Err(format!("patched ROM would be {} bytes, more than fits in memory", target_size));
+    }
+    if source_size != rom.len() || crc32(rom) != checksum(0) {
+        return Err("patch is for a different ROM".to_string());
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+    let broken = || "patch copies from outside the ROM".to_string();
+    while position < actions.len() {
+        let action = bps_number(actions, &mut position)?;
+        let length = (action >> 2) + 1;
+        // Every action writes, so the size given up front bounds them all
+        if length > target_size - target.len() {
+            return Err("patch writes past the patched ROM's size".to_string());
+        }
+        match action & 3 {
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(rom.get(start..start + length).ok_or_else(broken)?);
+            }
+            1 => {
+                target.extend_from_slice(actions.get(position..position + length).ok_or_else(broken)?);
+                position += length;
+            }
+            kind => {
+                let delta = bps_number(actions, &mut position)?;
+                let delta = if delta & 1 == 1 { -((delta >> 1) as isize) } else { (delta >> 1) as isize };
+                if kind == 2 {
+                    source_offset = source_offset.checked_add(delta).ok_or_else(broken)?;
+                    let start = usize::try_from(source_offset).map_err(|_| broken())?;
+                    target.extend_from_slice(rom.get(start..start + length).ok_or_else(broken)?);
+                    source_offset += length as isize;
+                } else {
+                    // Copies byte by byte since the range can overlap what's being written
+                    target_offset = target_offset.checked_add(delta).ok_or_else(broken)?;
+                    for _ in 0..length {
+                        let byte = *usize::try_from(target_offset).ok().and_then(|offset| target.get(offset)).ok_or_else(broken)?;
+                        target.push(byte);
+                        target_offset += 1;
+                    }
+                }
+            }
+        }
+    }
+    if target.len() != target_size || crc32(&target) != checksum(1) {
+        return Err("patched ROM doesn't match the patch's checksum".to_string());
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bps_encode(mut number: usize, out: &mut Vec<u8>) {
+        loop {
+            let bits = (number & 0x7F) as u8;
+            number >>= 7;
+            if number == 0 {
+                out.push(0x80 | bits);
+                return;
+            }
+            out.push(bits);
+            number -= 1;
+        }
+    }
+
+    // Source read, target read, source copy and target copy, in that order
+    fn bps_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        for number in [source.len(), target.len(), 0] {
+            bps_encode(number, &mut patch);
+        }
+        bps_encode((2 - 1) << 2, &mut patch);
+        bps_encode(((2 - 1) << 2) | 1, &mut patch);
+        patch.extend_from_slice(&[0xAA, 0xBB]);
+        bps_encode(((2 - 1) << 2) | 2, &mut patch);
+        bps_encode(6 << 1, &mut patch);
+        bps_encode(((3 - 1) << 2) | 3, &mut patch);
+        bps_encode(2 << 1, &mut patch);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let checksum = crc32(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn bps_uses_every_action() {
+        let source = [1, 2, 3, 4, 5, 6, 7, 8];
+        let target = [1, 2, 0xAA, 0xBB, 7, 8, 0xAA, 0xBB, 7];
+        assert_eq!(apply_bps(&source, &bps_patch(&source, &target)), Ok(target.to_vec()));
+    }
+
+    #[test]
+    fn bps_refuses_other_roms() {
+        let source = [1, 2, 3, 4, 5, 6, 7, 8];
+        let patch = bps_patch(&source, &[1, 2, 0xAA, 0xBB, 7, 8, 0xAA, 0xBB, 7]);
+        assert!(apply_bps(&[8, 7, 6, 5, 4, 3, 2, 1], &patch).is_err());
+        let mut corrupted = patch.clone();
+        corrupted[8] ^= 1;
+        assert!(apply_bps(&source, &corrupted).is_err());
+    }
+
+    #[test]
+    fn ips_applies_plain_and_run_records() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 9, 9]);
+        patch.extend_from_slice(&[0, 0, 4, 0, 0, 0, 3, 0x55]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply_ips(&[0; 4], &patch), Ok(vec![0, 9, 9, 0, 0x55, 0x55, 0x55]));
+
+        patch.extend_from_slice(&[0, 0, 2]);
+        assert_eq!(apply_ips(&[0; 4], &patch), Ok(vec![0, 9]));
+        assert!(apply_ips(&[0; 4], b"PATCH\0\0\x01\0\x02\x09").is_err());
+    }
+
+    #[test]
+    fn ips_stays_in_memory() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0, 1, 9]);
+        patch.extend_from_slice(b"EOF");
+        assert!(apply_ips(&[0; 4], &patch).is_err());
+    }
+
+    #[test]
+    fn byte_patches() {
+        let mut rom = vec![0; 4];
+        Patch::parse("0x202=12 34 56").unwrap().apply(&mut rom, 0x200).unwrap();
+        assert_eq!(rom, [0, 0, 0x12, 0x34, 0x56]);
+        assert!(Patch::parse("0x200=123").is_err());
+        assert!(Patch::parse("0x1FF=12").unwrap().apply(&mut rom, 0x200).is_err());
+        assert!(Patch::parse("ffffffffffffffff=00").unwrap().apply(&mut rom, 0x200).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Platform {
+    Chip8, // The original COSMAC VIP interpreter
+    Eti660,
+    Dream6800,
+    SuperChip, // HP48
+    XoChip,
+}
+
+// A range of memory the original machine kept for itself, programs loaded over it may not work
+pub struct Region {
+    pub start: usize,
+    pub end: usize, // Exclusive
+    pub name: &'static str,
+}
+
+// Where a program lives in memory. Starts out with the platform's defaults, the load address and
+// entry point can be changed for ROMs that expect something else
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MemoryMap {
+    pub size: usize,
+    pub load_address: usize,
+    pub entry_point: usize,
+}
+
+// The VIP interpreter kept its call stack in memory, growing down from here
+pub const VIP_STACK_TOP: usize = 0xED0;
+pub const VIP_STACK_SLOTS: usize = 24; // 0xEA0-0xECF, deeper calls would run into other memory
+
+impl MemoryMap {
+    // Largest ROM that fits between the load address and the end of memory
+    pub fn max_rom_size(&self) -> usize {
+        self.size.saturating_sub(self.load_address)
+    }
+}
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "eti660" => Some(Platform::Eti660),
+            "dream6800" => Some(Platform::Dream6800),
+            "schip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    // The name from_name takes
+    pub fn id(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::Eti660 => "eti660",
+            Platform::Dream6800 => "dream6800",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::Eti660 => "ETI-660",
+            Platform::Dream6800 => "DREAM 6800",
+            Platform::SuperChip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+
+    pub fn memory_map(&self) -> MemoryMap {
+        let size = match self {
+            Platform::XoChip => 0x10000,
+            _ => 0x1000,
+        };
+        // ETI-660 programs start after its larger interpreter
+        let load_address = match self {
+            Platform::Eti660 => 0x600,
+            _ => 0x200,
+        };
+        MemoryMap {
+            size,
+            load_address,
+            entry_point: load_address,
+        }
+    }
+
+    // Nested calls the interpreter had room for
+    pub fn stack_depth(&self) -> usize {
+        match self {
+            Platform::SuperChip | Platform::XoChip => 16,
+            _ => 12,
+        }
+    }
+
+    pub fn reserved_regions(&self) -> &'static [Region] {
+        match self {
+            Platform::Chip8 => &[
+                Region { start: 0x000, end: 0x200, name: "interpreter" },
+                Region { start: 0xEA0, end: 0xF00, name: "interpreter stack and variables" },
+                Region { start: 0xF00, end: 0x1000, name: "display buffer" },
+            ],
+            Platform::Eti660 => &[Region { start: 0x000, end: 0x600, name: "interpreter" }],
+            Platform::Dream6800 => &[Region { start: 0x000, end: 0x200, name: "CHIPOS variables, stack and display buffer" }],
+            // The HP48 runs the interpreter from its own memory, only the fonts live down there
+            Platform::SuperChip | Platform::XoChip => &[Region { start: 0x000, end: 0x200, name: "fonts" }],
+        }
+    }
+}
@@ -1,40 +1,52 @@
-use std::{process, time::{Duration, Instant}};
-
 pub struct DelayTimer {
     pub value: u8,
-    pub last_update: Instant,
-    pub interval: Duration,
+}
+
+// One subroutine call, kept so the call stack can be inspected
+#[derive(Clone, Copy)]
+pub struct StackFrame {
+    pub return_address: u16,
+    pub call_site: usize, // Address of the 2NNN that made the call
 }
 
 pub struct Processor {
     pub registers: [u8; 16],
     pub address_register: u16,
     pub program_counter : usize,
-    pub stack: [u16; 12],
-    pub stack_pointer: usize,
+    pub stack: Vec<StackFrame>,
+    pub stack_depth: usize, // Calls that fit before the stack overflows
     pub delay_timer: DelayTimer,
+    pub sound_timer: DelayTimer, // Counts down like the delay timer, the buzzer sounds while it's above 0
 }
 
 impl DelayTimer {
     pub fn new() -> Self {
         DelayTimer {
             value: 0,
-            last_update: Instant::now(),
-            interval: Duration::from_secs_f64(1.0 / 60.0),
         }
     }
 
+    // Called once per 60 Hz frame, not on wall-clock time, so runs stay reproducible
     pub fn clock(&mut self) {
-        if self.last_update.elapsed() >= self.interval {
-            if self.value > 0 {
-                self.value -= 1;
-            }
-            self.last_update = Instant::now();
+        if self.value > 0 {
+            self.value -= 1;
         }
     }
 }
 
 impl Processor {
+    pub fn new(stack_depth: usize) -> Self {
+        Processor {
+            registers: [0; 16],
+            address_register: 0,
+            program_counter: 0,
+            stack: Vec::with_capacity(stack_depth.min(64)), // Deep stacks are rarely used all the way
+            stack_depth,
+            delay_timer: DelayTimer::new(),
+            sound_timer: DelayTimer::new(),
+        }
+    }
+
     pub fn get_register(&self, index: u8) -> u8 {
         if index < 16 {
             self.registers[index as usize]
@@ -65,36 +77,19 @@ impl Processor {
         }
     }
 
-    pub fn push_stack(&mut self, value: u16) {
-        if self.stack_pointer < self.stack.len() {
-            self.stack[self.stack_pointer] = value;
-            self.stack_pointer += 1;
+    // Returns false on stack overflow
+    pub fn push_stack(&mut self, frame: StackFrame) -> bool {
+        if self.stack.len() < self.stack_depth {
+            self.stack.push(frame);
+            true
         } else {
-            eprintln!("Stack overflow, exiting");
-            process::exit(1);
+            false
         }
     }
 
-    pub fn pop_stack(&mut self) -> u16 {
-        if self.stack_pointer > 0 {
-            self.stack_pointer -= 1;
-            self.stack[self.stack_pointer]
-        } else {
-            eprintln!("Stack underflow, exiting");
-            process::exit(1);
-        }
+    // Returns None on stack underflow
+    pub fn pop_stack(&mut self) -> Option<StackFrame> {
+        self.stack.pop()
     }
 }
 
-impl Default for Processor {
-    fn default() -> Self {
-        Processor {
-            registers: [0; 16],
-            address_register: 0,
-            program_counter: 0,
-            stack: [0; 12],
-            stack_pointer: 0,
-            delay_timer: DelayTimer::new(),
-        }
-    }
-}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+
+// How many entries of each table the text report shows, the JSON one has everything
+const TEXT_REPORT_ROWS: usize = 20;
+
+#[derive(Default)]
+struct AddressStats {
+    count: u64,
+    opcode: u16, // Last opcode seen there, self modifying code can change it
+}
+
+#[derive(Default)]
+struct SubroutineStats {
+    calls: u64,
+    cycles: u64, // Instructions executed from the 2NNN up to the matching 00EE, nested calls included
+}
+
+// Counts where a ROM spends its instructions. Everything is counted in instructions rather than
+// time, so the numbers are the same at any speed
+pub struct Profiler {
+    output_path: String,
+    instructions: u64,
+    addresses: HashMap<usize, AddressStats>,
+    classes: HashMap<&'static str, u64>,
+    loops: HashMap<(usize, usize), u64>, // Backward jumps by (target, jump address)
+    subroutines: HashMap<usize, SubroutineStats>,
+    open_calls: Vec<(usize, u64)>, // Subroutine address and the instruction count it was called at
+    frames: u64,
+    frame_instructions: u64,
+    frame_budget: u64,
+    frame_min: u64,
+    frame_max: u64,
+    frames_ended_early: u64,
+}
+
+// Groups opcodes by what they do
+pub fn opcode_class(opcode: u16) -> &'static str {
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "clear",
+            0x00EE => "return",
+            _ => "system",
+        },
+        0x1000 => "jump",
+        0x2000 => "call",
+        0x3000 | 0x4000 | 0x5000 | 0x9000 => "skip",
+        0x6000 => "set register",
+        0x7000 => "add",
+        0x8000 => "arithmetic",
+        0xA000 => "set index",
+        0xB000 => "jump with offset",
+        0xC000 => "random",
+        0xD000 => "draw",
+        0xE000 => "key skip",
+        _ => match opcode & 0x00FF {
+            0x0A => "wait for key",
+            0x07 | 0x15 | 0x18 => "timers",
+            0x1E => "add to index",
+            0x29 | 0x30 => "font",
+            0x33 => "decimal",
+            0x55 | 0x65 => "save/load registers",
+            _ => "misc",
+        },
+    }
+}
+
+// Sorts a table by its counts, biggest first, and addresses for ties so reports are stable
+fn sorted_by_count<K: Ord + Copy, V>(table: &HashMap<K, V>, count: impl Fn(&V) -> u64) -> Vec<(K, &V)> {
+    let mut rows: Vec<(K, &V)> = table.iter().map(|(key, value)| (*key, value)).collect();
+    rows.sort_by(|a, b| count(b.1).cmp(&count(a.1)).then(a.0.cmp(&b.0)));
+    rows
+}
+
+impl Profiler {
+    pub fn new(output_path: &str) -> Self {
+        Profiler {
+            output_path: output_path.to_string(),
+            instructions: 0,
+            addresses: HashMap::new(),
+            classes: HashMap::new(),
+            loops: HashMap::new(),
+            subroutines: HashMap::new(),
+            open_calls: Vec::new(),
+            frames: 0,
+            frame_instructions: 0,
+            frame_budget: 0,
+            frame_min: u64::MAX,
+            frame_max: 0,
+            frames_ended_early: 0,
+        }
+    }
+
+    // Throws away everything counted so far, for when a different program is loaded
+    pub fn clear(&mut self) {
+        *self = Profiler::new(&self.output_path);
+    }
+
+    // Calls in progress can't return anymore once the machine is reset
+    pub fn reset(&mut self) {
+        self.open_calls.clear();
+    }
+
+    // Called for every instruction that ran without error
+    pub fn record_instruction(&mut self, address: usize, opcode: u16) {
+        self.instructions += 1;
+        let stats = self.addresses.entry(address).or_default();
+        stats.count += 1;
+        stats.opcode = opcode;
+        *self.classes.entry(opcode_class(opcode)).or_default() += 1;
+
+        let target = (opcode & 0x0FFF) as usize;
+        match opcode & 0xF000 {
+            0x1000 if target <= address => *self.loops.entry((target, address)).or_default() += 1,
+            0x2000 => self.open_calls.push((target, self.instructions)),
+            _ if opcode == 0x00EE => {
+                if let Some((subroutine, called_at)) = self.open_calls.pop() {
+                    let stats = self.subroutines.entry(subroutine).or_default();
+                    stats.calls += 1;
+                    stats.cycles += self.instructions - called_at;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn record_frame(&mut self, instructions: u64, budget: u64, ended_early: bool) {
+        self.frames += 1;
+        self.frame_instructions += instructions;
+        self.frame_budget += budget;
+        self.frame_min = self.frame_min.min(instructions);
+        self.frame_max = self.frame_max.max(instructions);
+        if ended_early {
+            self.frames_ended_early += 1;
+        }
+    }
+
+    // Instructions executed between the start and end of a loop, however they got there
+    fn loop_instructions(&self, start: usize, end: usize) -> u64 {
+        self.addresses.iter().filter(|(address, _)| (start..=end).contains(*address)).map(|(_, stats)| stats.count).sum()
+    }
+
+    fn share(&self, count: u64) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.instructions as f64
+        }
+    }
+
+    fn frame_average(&self) -> f64 {
+        if self.frames == 0 {
+            0.0
+        } else {
+            self.frame_instructions as f64 / self.frames as f64
+        }
+    }
+
+    fn budget_average(&self) -> f64 {
+        if self.frames == 0 {
+            0.0
+        } else {
+            self.frame_budget as f64 / self.frames as f64
+        }
+    }
+
+    pub fn text_report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "Profile of {} instructions over {} frames", self.instructions, self.frames);
+        if self.frames > 0 {
+            let _ = writeln!(report, "Instructions per frame: {:.1} on average out of {:.1}, min {}, max {}, {} frames ended early waiting for the display", self.frame_average(), self.budget_average(), self.frame_min, self.frame_max, self.frames_ended_early);
+        }
+
+        let _ = writeln!(report, "\nHottest addresses:");
+        for (address, stats) in sorted_by_count(&self.addresses, |stats| stats.count).into_iter().take(TEXT_REPORT_ROWS) {
+            let _ = writeln!(report, "  {:03X}  {:04X}  {:>10}  {:5.1}%", address, stats.opcode, stats.count, self.share(stats.count));
+        }
+
+        let _ = writeln!(report, "\nHot loops:");
+        for ((start, end), iterations) in sorted_by_count(&self.loops, |iterations| *iterations).into_iter().take(TEXT_REPORT_ROWS) {
+            let instructions = self.loop_instructions(start, end);
+            let _ = writeln!(report, "  {:03X}-{:03X}  {:>10} iterations  {:5.1}% of instructions", start, end, iterations, self.share(instructions));
+        }
+
+        let _ = writeln!(report, "\nMost called subroutines:");
+        for (address, stats) in sorted_by_count(&self.subroutines, |stats| stats.calls).into_iter().take(TEXT_REPORT_ROWS) {
+            let average = stats.cycles as f64 / stats.calls as f64;
+            let _ = writeln!(report, "  {:03X}  {:>10} calls  {:>10} instructions  {:8.1} per call", address, stats.calls, stats.cycles, average);
+        }
+
+        let _ = writeln!(report, "\nInstruction classes:");
+        for (class, count) in sorted_by_count(&self.classes, |count| *count) {
+            let _ = writeln!(report, "  {:<20}  {:>10}  {:5.1}%", class, count, self.share(*count));
+        }
+        report
+    }
+
+    pub fn json_report(&self) -> String {
+        let addresses: Vec<String> = sorted_by_count(&self.addresses, |stats| stats.count)
+            .into_iter()
+            .map(|(address, stats)| format!("{{\"address\": {}, \"opcode\": {}, \"count\": {}}}", address, stats.opcode, stats.count))
+            .collect();
+        let loops: Vec<String> = sorted_by_count(&self.loops, |iterations| *iterations)
+            .into_iter()
+            .map(|((start, end), iterations)| {
+                format!("{{\"start\": {}, \"end\": {}, \"iterations\": {}, \"instructions\": {}}}", start, end, iterations, self.loop_instructions(start, end))
+            })
+            .collect();
+        let subroutines: Vec<String> = sorted_by_count(&self.subroutines, |stats| stats.calls)
+            .into_iter()
+            .map(|(address, stats)| format!("{{\"address\": {}, \"calls\": {}, \"cycles\": {}}}", address, stats.calls, stats.cycles))
+            .collect();
+        let classes: Vec<String> = sorted_by_count(&self.classes, |count| *count)
+            .into_iter()
+            .map(|(class, count)| format!("{{\"class\": \"{}\", \"count\": {}}}", class, count))
+            .collect();
+
+        format!(
+            "{{\n  \"instructions\": {},\n  \"frames\": {},\n  \"instructions_per_frame\": {{\"average\": {:.3}, \"budget\": {:.3}, \"min\": {}, \"max\": {}, \"ended_early\": {}}},\n  \"addresses\": [{}],\n  \"loops\": [{}],\n  \"subroutines\": [{}],\n  \"classes\": [{}]\n}}\n",
+            self.instructions,
+            self.frames,
+            self.frame_average(),
+            self.budget_average(),
+            if self.frames == 0 { 0 } else { self.frame_min },
+            self.frame_max,
+            self.frames_ended_early,
+            addresses.join(", "),
+            loops.join(", "),
+            subroutines.join(", "),
+            classes.join(", ")
+        )
+    }
+
+    // The report is JSON when the file name ends in .json, text otherwise
+    pub fn save(&self) -> io::Result<&str> {
+        let report = if self.output_path.ends_with(".json") { self.json_report() } else { self.text_report() };
+        fs::write(&self.output_path, report)?;
+        Ok(&self.output_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Calls a subroutine once, then goes around a two instruction loop twice
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new("profile.json");
+        for (address, opcode) in [(0x200, 0x2300), (0x300, 0x7001), (0x302, 0x00EE), (0x202, 0x7101), (0x204, 0x1202), (0x202, 0x7101), (0x204, 0x1202)] {
+            profiler.record_instruction(address, opcode);
+        }
+        profiler.record_frame(4, 10, false);
+        profiler.record_frame(3, 10, true);
+        profiler
+    }
+
+    #[test]
+    fn counts() {
+        let profiler = profile();
+        assert_eq!(profiler.instructions, 7);
+        assert_eq!(profiler.addresses[&0x202].count, 2);
+        assert_eq!(profiler.classes["add"], 3);
+        assert_eq!(profiler.loops[&(0x202, 0x204)], 2);
+        assert_eq!(profiler.loop_instructions(0x202, 0x204), 4);
+        assert_eq!((profiler.subroutines[&0x300].calls, profiler.subroutines[&0x300].cycles), (1, 2));
+        assert_eq!((profiler.frame_min, profiler.frame_max, profiler.frames_ended_early), (3, 4, 1));
+        assert_eq!(profiler.frame_average(), 3.5);
+    }
+
+    #[test]
+    fn reset_drops_open_calls() {
+        let mut profiler = Profiler::new("profile.txt");
+        profiler.record_instruction(0x200, 0x2300);
+        profiler.reset();
+        profiler.record_instruction(0x302, 0x00EE);
+        assert!(profiler.subroutines.is_empty());
+    }
+
+    #[test]
+    fn reports() {
+        let profiler = profile();
+        let text = profiler.text_report();
+        assert!(text.starts_with("Profile of 7 instructions over 2 frames\n"));
+        assert!(text.contains("  202-204           2 iterations   57.1% of instructions"));
+        let json = profiler.json_report();
+        assert!(json.contains("\"instructions_per_frame\": {\"average\": 3.500, \"budget\": 10.000, \"min\": 3, \"max\": 4, \"ended_early\": 1}"));
+        assert!(json.contains("\"subroutines\": [{\"address\": 768, \"calls\": 1, \"cycles\": 2}]"));
+        // Ties go by address
+        assert!(json.contains("\"addresses\": [{\"address\": 514, \"opcode\": 28929, \"count\": 2}, {\"address\": 516, "));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(opcode_class(0x00E0), "clear");
+        assert_eq!(opcode_class(0x0123), "system");
+        assert_eq!(opcode_class(0xE09E), "key skip");
+        assert_eq!(opcode_class(0xF30A), "wait for key");
+        assert_eq!(opcode_class(0xF365), "save/load registers");
+        assert_eq!(opcode_class(0xF375), "misc");
+    }
+}
@@ -1,73 +1,171 @@
-use std::{sync::{Arc, Mutex}, usize};
 use macroquad::prelude::*;
+use crate::controller::Status;
+use crate::display::*;
+use crate::palette::*;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Persistence {
+    Off,
+    Decay,      // Pixels fade out by the decay rate after turning off
+    Blend,      // Show the OR of the last two frames
+}
+
+impl Persistence {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Persistence::Off => "off",
+            Persistence::Decay => "decay",
+            Persistence::Blend => "blend",
+        }
+    }
+}
 
 pub struct Renderer {
-    pub pixels_mutex: Arc<Mutex<Vec<u8>>>,
+    pub display: Display, // Last completed frame
+    pub integer_scaling: bool,
+    pub show_hud: bool,
+    pub reserved_width: f32, // Taken from the right side of the window by the on-screen keypad
+    palettes: Vec<Palette>,
+    current_palette: usize,
+    pub persistence: Persistence,
+    pub decay_rate: f32,     // Fraction of brightness a pixel loses every frame after turning off
+    intensity: Vec<f32>,     // Brightness of every pixel for the decay mode
+    last_value: Vec<u8>,     // Color every pixel had when it was last on
+    previous_pixels: Vec<u8>,
 }
 
 impl Renderer {
     pub fn new() -> Self {
         Renderer {
-            pixels_mutex: Arc::new(Mutex::new(vec![0u8; 64 * 32])),
+            display: Display::new(),
+            integer_scaling: false,
+            show_hud: true,
+            reserved_width: 0.0,
+            palettes: builtin_palettes(),
+            current_palette: 0,
+            persistence: Persistence::Off,
+            decay_rate: 0.35,
+            intensity: vec![0.0; 64 * 32],
+            last_value: vec![0; 64 * 32],
+            previous_pixels: vec![0; 64 * 32],
         }
     }
 
-    pub fn clear_pixels(&mut self) {
-        let mut pixels = self.pixels_mutex.lock().unwrap();
+    // Cycles off -> decay -> blend, decay uses the configured rate
+    pub fn next_persistence(&mut self) -> Persistence {
+        self.persistence = match self.persistence {
+            Persistence::Off => Persistence::Decay,
+            Persistence::Decay => Persistence::Blend,
+            Persistence::Blend => Persistence::Off,
+        };
+        self.persistence
+    }
 
-        for pixel in pixels.iter_mut() {
-            *pixel = 0;
-        }
+    pub fn add_palettes(&mut self, palettes: Vec<Palette>) {
+        self.palettes.extend(palettes);
     }
 
-    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: Vec<u8>) -> bool {
-        let mut pixels = self.pixels_mutex.lock().unwrap();
-        let mut current_y = y;
-        let mut written_pixel = false;
-        for block in sprite.iter() {
-            if current_y >= 32 {
-                break;
+    pub fn select_palette(&mut self, name: &str) -> bool {
+        match self.palettes.iter().position(|palette| palette.name == name) {
+            Some(index) => {
+                self.current_palette = index;
+                true
             }
-            for i in 0..8 {
-                if (i + x as usize) >= 64 {
-                    break;
-                }
-                let current_pixel: usize = (current_y as usize) * 64 + i + x as usize;
-                if pixels[current_pixel] != ((block << i) & 128) >> 7 && !written_pixel {
-                    written_pixel = true;
-                }
-                if (((block << i) & 128) >> 7) == 1 {
-                    if pixels[current_pixel] == 0 {
-                        pixels[current_pixel] = 1;
-                    } else {
-                        pixels[current_pixel] = 0;
-                    }
-                }
+            None => false,
+        }
+    }
+
+    pub fn colors(&self) -> [Color; 4] {
+        self.palettes[self.current_palette].colors
+    }
+
+    pub fn next_palette(&mut self) -> &str {
+        self.current_palette = (self.current_palette + 1) % self.palettes.len();
+        &self.palettes[self.current_palette].name
+    }
+
+    // Takes a completed frame from the emulator. The renderer never sees a half drawn frame.
+    // Persistence moves on here, once per emulated frame, however often the window redraws
+    pub fn present(&mut self, display: &Display) {
+        if display.width != self.display.width || display.height != self.display.height {
+            self.intensity = vec![0.0; display.width * display.height];
+            self.last_value = vec![0; display.width * display.height];
+            self.previous_pixels = vec![0; display.width * display.height];
+        } else {
+            self.previous_pixels.copy_from_slice(&self.display.pixels);
+        }
+        self.display.clone_from(display);
 
+        for (it, pixel) in self.display.pixels.iter().enumerate() {
+            if *pixel != 0 {
+                self.intensity[it] = 1.0;
+                self.last_value[it] = *pixel;
+            } else if self.persistence == Persistence::Decay {
+                self.intensity[it] *= 1.0 - self.decay_rate;
+            } else {
+                self.intensity[it] = 0.0;
             }
-            current_y += 1;
         }
-        written_pixel
     }
 
-    pub fn do_render(&mut self) {
-        clear_background(BLACK);
-
-        let pixel_size = screen_height() / 32.0;
-        
-        let mut current_x: f32;
-        let mut current_y: f32;
-        let mut it = 0;
-        let pixels = self.pixels_mutex.lock().unwrap();
-        for pixel in pixels.iter() {
-            current_x = (it as f32 % 64.0) * pixel_size;
-            current_y = ((it / 64) * pixel_size as i32) as f32;
-            if *pixel == 1 {
-                draw_rectangle(current_x, current_y, pixel_size, pixel_size, WHITE);
-            }
-            it += 1;
+    // Largest pixel size that fits the window while keeping the display's aspect ratio, and
+    // the offset that centers it. Returns (x, y, pixel size)
+    fn viewport(&self) -> (f32, f32, f32) {
+        let width = screen_width() - self.reserved_width;
+        let mut pixel_size = (width / self.display.width as f32).min(screen_height() / self.display.height as f32);
+        if self.integer_scaling && pixel_size >= 1.0 {
+            pixel_size = pixel_size.floor();
         }
+        let x = ((width - pixel_size * self.display.width as f32) / 2.0).floor();
+        let y = ((screen_height() - pixel_size * self.display.height as f32) / 2.0).floor();
+        (x, y, pixel_size)
     }
-}
 
+    pub fn do_render(&self) {
+        let colors = self.colors();
+        clear_background(BLACK); // Letterbox around the display
+
+        let (origin_x, origin_y, pixel_size) = self.viewport();
+        draw_rectangle(origin_x, origin_y, pixel_size * self.display.width as f32, pixel_size * self.display.height as f32, colors[0]);
+
+        let pixels = &self.display.pixels;
+        for (it, pixel) in pixels.iter().enumerate() {
+            let current_x = origin_x + (it % self.display.width) as f32 * pixel_size;
+            let current_y = origin_y + (it / self.display.width) as f32 * pixel_size;
+
+            let color = match self.persistence {
+                Persistence::Blend if *pixel == 0 && self.previous_pixels[it] != 0 => Some(colors[self.previous_pixels[it] as usize & 3]),
+                Persistence::Decay if *pixel == 0 && self.intensity[it] > 0.02 => {
+                    let on_color = colors[self.last_value[it] as usize & 3];
+                    let fade = self.intensity[it];
+                    Some(Color::new(
+                        colors[0].r + (on_color.r - colors[0].r) * fade,
+                        colors[0].g + (on_color.g - colors[0].g) * fade,
+                        colors[0].b + (on_color.b - colors[0].b) * fade,
+                        1.0,
+                    ))
+                }
+                _ if *pixel != 0 => Some(colors[*pixel as usize & 3]),
+                _ => None,
+            };
+            if let Some(color) = color {
+                draw_rectangle(current_x, current_y, pixel_size, pixel_size, color);
+            }
+        }
+    }
 
+    pub fn draw_hud(&self, status: &Status) {
+        if !self.show_hud {
+            return;
+        }
+        let mut text = format!("{} IPF  {}", status.instructions_per_frame, status.speed.name());
+        if status.halted {
+            text.push_str("  HALTED");
+        } else if status.paused {
+            text.push_str("  PAUSED");
+        }
+        let dimensions = measure_text(&text, None, 20, 1.0);
+        draw_rectangle(4.0, 4.0, dimensions.width + 12.0, dimensions.height + 12.0, Color::new(0.0, 0.0, 0.0, 0.6));
+        draw_text(&text, 10.0, 10.0 + dimensions.offset_y, 20.0, WHITE);
+    }
+}
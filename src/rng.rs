@@ -0,0 +1,192 @@
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+// Source of the random bytes used by CXNN. Implementations must be deterministic for a given
+// seed so runs and movie replays can be reproduced
+pub trait RandomSource: Send {
+    fn next_byte(&mut self) -> u8;
+
+    fn kind(&self) -> RngKind;
+
+    // Enough to put the generator back where it was with restore, for save states
+    fn state(&self) -> Vec<u64>;
+
+    // Returns false when the state doesn't belong to this kind of generator
+    fn restore(&mut self, state: &[u64]) -> bool;
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RngKind {
+    Seeded, // General purpose PRNG, good distribution
+    Vip,    // Mimics the COSMAC VIP interpreter, with its poor distribution
+}
+
+impl RngKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "seeded" => Some(RngKind::Seeded),
+            "vip" => Some(RngKind::Vip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RngKind::Seeded => "seeded",
+            RngKind::Vip => "vip",
+        }
+    }
+
+    pub fn create(&self, seed: u64) -> Box<dyn RandomSource> {
+        match self {
+            RngKind::Seeded => Box::new(SeededRandom::new(seed)),
+            RngKind::Vip => Box::new(VipRandom::new(seed)),
+        }
+    }
+}
+
+// ChaCha8 gives the same stream for a seed in every version, unlike StdRng, so seeds and
+// movies keep working across updates. Its position in the stream is all the state there is
+pub struct SeededRandom {
+    rng: ChaCha8Rng,
+    seed: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        let mut key = [0u8; 32];
+        key[..8].copy_from_slice(&seed.to_le_bytes());
+        SeededRandom { rng: ChaCha8Rng::from_seed(key), seed }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_byte(&mut self) -> u8 {
+        self.rng.next_u32() as u8
+    }
+
+    fn kind(&self) -> RngKind {
+        RngKind::Seeded
+    }
+
+    fn state(&self) -> Vec<u64> {
+        let position = self.rng.get_word_pos();
+        vec![self.seed, position as u64, (position >> 64) as u64]
+    }
+
+    fn restore(&mut self, state: &[u64]) -> bool {
+        let [seed, low, high] = state else {
+            return false;
+        };
+        *self = SeededRandom::new(*seed);
+        self.rng.set_word_pos(((*high as u128) << 64) | *low as u128);
+        true
+    }
+}
+
+// Page 1 of the VIP's CHIP-8 interpreter, 0x100-0x1FF, which its CXNN routine reads from
+const VIP_INTERPRETER_PAGE: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x45, 0xA3, 0x98, 0x56, 0xD4, 0xF8, 0x81, 0xBC,
+    0xF8, 0x95, 0xAC, 0x22, 0xDC, 0x12, 0x56, 0xD4, 0x06, 0xB8, 0xD4, 0x06, 0xA8, 0xD4, 0x64, 0x0A,
+    0x01, 0xE6, 0x8A, 0xF4, 0xAA, 0x3B, 0x28, 0x9A, 0xFC, 0x01, 0xBA, 0xD4, 0xF8, 0x81, 0xBA, 0x06,
+    0xFA, 0x0F, 0xAA, 0x0A, 0xAA, 0xD4, 0xE6, 0x06, 0xBF, 0x93, 0xBE, 0xF8, 0x1B, 0xAE, 0x2A, 0x1A,
+    0xF8, 0x00, 0x5A, 0x0E, 0xF5, 0x3B, 0x4B, 0x56, 0x0A, 0xFC, 0x01, 0x5A, 0x30, 0x40, 0x4E, 0xF6,
+    0x3B, 0x3C, 0x9F, 0x56, 0x2A, 0x2A, 0xD4, 0x00, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x07, 0x5A,
+    0x87, 0xF3, 0x17, 0x1A, 0x3A, 0x5B, 0x12, 0xD4, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x0A, 0x57,
+    0x87, 0xF3, 0x17, 0x1A, 0x3A, 0x6B, 0x12, 0xD4, 0x15, 0x85, 0x22, 0x73, 0x95, 0x52, 0x25, 0x45,
+    0xA5, 0x86, 0xFA, 0x0F, 0xB5, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x82, 0x15, 0x15, 0xD4, 0x45, 0xE6,
+    0xF3, 0x3A, 0x88, 0xD4, 0x45, 0x07, 0x30, 0x8C, 0x45, 0x07, 0x30, 0x84, 0xE6, 0x62, 0x26, 0x45,
+    0xA3, 0x36, 0x88, 0xD4, 0x3E, 0x88, 0xD4, 0xF8, 0xF0, 0xA7, 0xE7, 0x45, 0xF4, 0xA5, 0x86, 0xFA,
+    0x0F, 0x3B, 0xB2, 0xFC, 0x01, 0xB5, 0xD4, 0x45, 0x56, 0xD4, 0x45, 0xE6, 0xF4, 0x56, 0xD4, 0x45,
+    0xFA, 0x0F, 0x3A, 0xC4, 0x07, 0x56, 0xD4, 0xAF, 0x22, 0xF8, 0xD3, 0x73, 0x8F, 0xF9, 0xF0, 0x52,
+    0xE6, 0x07, 0xD2, 0x56, 0xF8, 0xFF, 0xA6, 0xF8, 0x00, 0x7E, 0x56, 0xD4, 0x19, 0x89, 0xAE, 0x93,
+    0xBE, 0x99, 0xEE, 0xF4, 0x56, 0x76, 0xE6, 0xF4, 0xB9, 0x56, 0x45, 0xF2, 0x56, 0xD4, 0x45, 0xAA,
+    0x86, 0xFA, 0x0F, 0xBA, 0xD4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00, 0x4B,
+];
+
+// The VIP interpreter had no real RNG: CXNN bumps R9, reads the interpreter byte R9.0 points
+// at and folds it into R9.1, which is the result. Sequences are short and biased, which some
+// games were tuned around. R9 isn't set up by the interpreter, so the seed picks where it starts
+pub struct VipRandom {
+    r9: u16,
+}
+
+impl VipRandom {
+    pub fn new(seed: u64) -> Self {
+        VipRandom { r9: seed as u16 }
+    }
+}
+
+impl RandomSource for VipRandom {
+    // INC R9, then D = M(0x100 + R9.0) + R9.1 stored to VX, SHRC and ADD VX again into R9.1
+    fn next_byte(&mut self) -> u8 {
+        self.r9 = self.r9.wrapping_add(1);
+        let (sum, carry) = VIP_INTERPRETER_PAGE[(self.r9 & 0xFF) as usize].overflowing_add((self.r9 >> 8) as u8);
+        let shifted = (sum >> 1) | ((carry as u8) << 7);
+        let value = shifted.wrapping_add(sum);
+        self.r9 = ((value as u16) << 8) | (self.r9 & 0xFF);
+        value
+    }
+
+    fn kind(&self) -> RngKind {
+        RngKind::Vip
+    }
+
+    fn state(&self) -> Vec<u64> {
+        vec![self.r9 as u64]
+    }
+
+    fn restore(&mut self, state: &[u64]) -> bool {
+        let [r9] = state else {
+            return false;
+        };
+        self.r9 = *r9 as u16;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(rng: &mut dyn RandomSource, count: usize) -> Vec<u8> {
+        (0..count).map(|_| rng.next_byte()).collect()
+    }
+
+    #[test]
+    fn seeded_stream_is_fixed() {
+        let mut first = SeededRandom::new(12345);
+        let mut second = SeededRandom::new(12345);
+        assert_eq!(draw(&mut first, 64), draw(&mut second, 64));
+        assert_ne!(draw(&mut SeededRandom::new(0), 64), draw(&mut SeededRandom::new(1), 64));
+    }
+
+    #[test]
+    fn restore_continues_the_stream() {
+        for kind in [RngKind::Seeded, RngKind::Vip] {
+            let mut rng = kind.create(12345);
+            draw(rng.as_mut(), 100);
+            let state = rng.state();
+            let expected = draw(rng.as_mut(), 32);
+            let mut restored = kind.create(0);
+            assert!(restored.restore(&state));
+            assert_eq!(draw(restored.as_mut(), 32), expected);
+        }
+    }
+
+    #[test]
+    fn restore_rejects_other_kinds() {
+        let state = SeededRandom::new(1).state();
+        assert!(!VipRandom::new(1).restore(&state));
+        assert!(!SeededRandom::new(1).restore(&VipRandom::new(1).state()));
+    }
+
+    #[test]
+    fn vip_values_vary() {
+        for seed in [0, 12345, 0xFFFF] {
+            let values = draw(&mut VipRandom::new(seed), 256);
+            let distinct = values.iter().collect::<std::collections::HashSet<_>>().len();
+            assert!(distinct > 32, "seed {} only gave {} distinct values", seed, distinct);
+        }
+    }
+}
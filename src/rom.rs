@@ -0,0 +1,195 @@
+use crate::movie::hash_rom;
+use crate::patch::*;
+use crate::platform::*;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub enum RomError {
+    Io(io::Error),
+    Empty,
+    Patch(String),
+    TooLarge { size: usize, max_size: usize, load_address: usize, platform: Platform },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::Empty => write!(f, "ROM is empty"),
+            RomError::Patch(message) => write!(f, "Failed to patch ROM: {}", message),
+            RomError::TooLarge { size, max_size, load_address, platform } => {
+                write!(f, "ROM is {} bytes but only {} bytes fit in {} memory when loaded at {:03X}", size, max_size, platform.name(), load_address)
+            }
+        }
+    }
+}
+
+// A ROM that was checked to fit in memory, along with anything suspicious found about it
+pub struct Rom {
+    pub data: Vec<u8>,
+    pub hash: String, // Of the file as it is on disk, so patching doesn't lose what's kept per ROM
+    pub warnings: Vec<String>,
+}
+
+// Patches go on before the checks, so they can fix whatever the checks would find
+pub fn load_rom(file_path: &str, platform: Platform, memory_map: MemoryMap, patches: &[Patch]) -> Result<Rom, RomError> {
+    let mut data = fs::read(file_path).map_err(RomError::Io)?;
+    let hash = hash_rom(&data);
+    for patch in patches {
+        patch.apply(&mut data, memory_map.load_address).map_err(RomError::Patch)?;
+    }
+    validate_rom(data, hash, platform, memory_map)
+}
+
+pub fn validate_rom(data: Vec<u8>, hash: String, platform: Platform, memory_map: MemoryMap) -> Result<Rom, RomError> {
+    if data.is_empty() {
+        return Err(RomError::Empty);
+    }
+    if data.len() > memory_map.max_rom_size() {
+        return Err(RomError::TooLarge {
+            size: data.len(),
+            max_size: memory_map.max_rom_size(),
+            load_address: memory_map.load_address,
+            platform,
+        });
+    }
+
+    let mut warnings = Vec::new();
+    // Instructions are 2 bytes, but programs often end with an odd sized sprite so it's harmless
+    if !data.len().is_multiple_of(2) {
+        warnings.push(format!("ROM has an odd length ({} bytes), it may be truncated", data.len()));
+    }
+    let rom_end = memory_map.load_address + data.len();
+    for region in platform.reserved_regions() {
+        if memory_map.load_address < region.end && region.start < rom_end {
+            warnings.push(format!("ROM overlaps the {} at {:03X}-{:03X}", region.name, region.start, region.end - 1));
+        }
+    }
+    if !(memory_map.load_address..rom_end).contains(&memory_map.entry_point) {
+        warnings.push(format!("Entry point {:03X} is outside of the ROM", memory_map.entry_point));
+    }
+    if let Some((detected, reason)) = detect_from_content(&data) {
+        if detected != platform {
+            warnings.push(format!("ROM looks like {} ({}) but runs as {}", detected.name(), reason, platform.name()));
+        }
+    }
+    Ok(Rom { data, hash, warnings })
+}
+
+// Returns the platform guessed from the extension, or else from the instructions the ROM uses,
+// with a description of how it was guessed
+pub fn detect_platform(file_path: &str, data: &[u8]) -> Option<(Platform, String)> {
+    let extension = Path::new(file_path).extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("ch8") => Some((Platform::Chip8, ".ch8 extension".to_string())),
+        Some("sc8") => Some((Platform::SuperChip, ".sc8 extension".to_string())),
+        Some("xo8") => Some((Platform::XoChip, ".xo8 extension".to_string())),
+        // CHIP-8X adds color instructions on top of CHIP-8, the rest runs fine
+        Some("c8x") => Some((Platform::Chip8, ".c8x extension, CHIP-8X color instructions are unsupported".to_string())),
+        _ => detect_from_content(data),
+    }
+}
+
+// Looks for instructions only later platforms have. Sprite data can look like anything, so the
+// opcodes checked are ones unlikely to show up in graphics and a couple of them are needed
+fn detect_from_content(data: &[u8]) -> Option<(Platform, String)> {
+    let mut super_chip_markers = 0;
+    let mut xo_chip_markers = 0;
+    for chunk in data.chunks_exact(2) {
+        let opcode = ((chunk[0] as u16) << 8) | chunk[1] as u16;
+        match opcode {
+            0x00FB..=0x00FF => super_chip_markers += 1, // Scroll, exit, low/high resolution
+            _ if opcode & 0xFFF0 == 0x00C0 => super_chip_markers += 1, // Scroll down
+            _ if opcode & 0xF0FF == 0xF030 || opcode & 0xF0FF == 0xF075 || opcode & 0xF0FF == 0xF085 => super_chip_markers += 1,
+            0xF002 => xo_chip_markers += 1, // Load audio pattern
+            0xF101 | 0xF201 | 0xF301 => xo_chip_markers += 1, // Select plane
+            _ if opcode & 0xF0FF == 0xF03A => xo_chip_markers += 1, // Set pitch
+            _ if opcode & 0xF00F == 0x5002 || opcode & 0xF00F == 0x5003 => xo_chip_markers += 1, // Save/load register range
+            _ => {}
+        }
+    }
+
+    if xo_chip_markers >= 2 {
+        Some((Platform::XoChip, format!("{} XO-CHIP instructions", xo_chip_markers)))
+    } else if super_chip_markers >= 2 {
+        Some((Platform::SuperChip, format!("{} SUPER-CHIP instructions", super_chip_markers)))
+    } else if data.len() > Platform::Chip8.memory_map().max_rom_size() {
+        Some((Platform::XoChip, "too large for 4K of memory".to_string()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(data: Vec<u8>, platform: Platform) -> Result<Rom, RomError> {
+        validate_rom(data, String::new(), platform, platform.memory_map())
+    }
+
+    fn warnings(data: Vec<u8>, platform: Platform) -> Vec<String> {
+        validate(data, platform).ok().unwrap().warnings
+    }
+
+    #[test]
+    fn sizes() {
+        assert!(matches!(validate(Vec::new(), Platform::Chip8), Err(RomError::Empty)));
+        assert!(validate(vec![0; 0xE00], Platform::Chip8).is_ok());
+        assert!(matches!(validate(vec![0; 0xE01], Platform::Chip8), Err(RomError::TooLarge { size: 0xE01, max_size: 0xE00, load_address: 0x200, .. })));
+        // ETI-660 programs load higher up and get less room
+        assert!(matches!(validate(vec![0; 0xA01], Platform::Eti660), Err(RomError::TooLarge { max_size: 0xA00, load_address: 0x600, .. })));
+        assert!(validate(vec![0; 0xA01], Platform::XoChip).is_ok());
+    }
+
+    #[test]
+    fn warnings_about_the_layout() {
+        assert!(warnings(vec![0x12, 0x00], Platform::Chip8).is_empty());
+        assert_eq!(warnings(vec![0x12, 0x00, 0xFF], Platform::Chip8), vec!["ROM has an odd length (3 bytes), it may be truncated"]);
+        assert_eq!(warnings(vec![0; 0xCA2], Platform::Chip8), vec!["ROM overlaps the interpreter stack and variables at EA0-EFF"]);
+        assert_eq!(warnings(vec![0; 0xD02], Platform::Chip8).len(), 2);
+        // Only the VIP kept anything up there
+        assert!(warnings(vec![0; 0xD02], Platform::SuperChip).is_empty());
+
+        let memory_map = MemoryMap { entry_point: 0x300, ..Platform::Chip8.memory_map() };
+        let rom = validate_rom(vec![0x12, 0x00], String::new(), Platform::Chip8, memory_map).ok().unwrap();
+        assert_eq!(rom.warnings, vec!["Entry point 300 is outside of the ROM"]);
+    }
+
+    #[test]
+    fn platform_mismatch() {
+        let data = vec![0x00, 0xFF, 0x00, 0xFB];
+        assert_eq!(warnings(data.clone(), Platform::Chip8), vec!["ROM looks like SUPER-CHIP (2 SUPER-CHIP instructions) but runs as CHIP-8"]);
+        assert!(warnings(data, Platform::SuperChip).is_empty());
+    }
+
+    #[test]
+    fn extensions() {
+        let detected = |file_path: &str| detect_platform(file_path, &[]).map(|(platform, _)| platform);
+        assert_eq!(detected("roms/pong.ch8"), Some(Platform::Chip8));
+        assert_eq!(detected("PONG.CH8"), Some(Platform::Chip8));
+        assert_eq!(detected("ant.sc8"), Some(Platform::SuperChip));
+        assert_eq!(detected("ant.Xo8"), Some(Platform::XoChip));
+        assert_eq!(detected("color.c8x"), Some(Platform::Chip8));
+        assert_eq!(detected("game.bin"), None);
+        assert_eq!(detected("ch8"), None);
+        // The extension wins over the content
+        assert_eq!(detect_platform("game.ch8", &[0x00, 0xFF, 0x00, 0xFB]).map(|(platform, _)| platform), Some(Platform::Chip8));
+    }
+
+    #[test]
+    fn content() {
+        let detected = |data: &[u8]| detect_from_content(data).map(|(platform, _)| platform);
+        assert_eq!(detected(&[0x12, 0x00, 0x00, 0xE0]), None);
+        // A single SUPER-CHIP looking word is most likely sprite data
+        assert_eq!(detected(&[0x12, 0x00, 0x00, 0xFF, 0x81, 0x81]), None);
+        assert_eq!(detected(&[0x00, 0xC4, 0xF2, 0x75]), Some(Platform::SuperChip));
+        // Markers have to be on instruction boundaries
+        assert_eq!(detected(&[0x00, 0x00, 0xFF, 0x00, 0xFD, 0x00]), None);
+        assert_eq!(detected(&[0xF0, 0x02, 0xF1, 0x01, 0x00, 0xFF]), Some(Platform::XoChip));
+        assert_eq!(detected(&vec![0; 0xE01]), Some(Platform::XoChip));
+        assert_eq!(detect_platform("game.bin", &[0x00, 0xFB, 0x00, 0xFC]), Some((Platform::SuperChip, "2 SUPER-CHIP instructions".to_string())));
+    }
+}
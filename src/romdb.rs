@@ -0,0 +1,115 @@
+use crate::gamepad::*;
+use crate::patch::*;
+use crate::platform::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+// Settings for a ROM that can't be guessed from the file alone
+#[derive(Clone, Default)]
+pub struct RomInfo {
+    pub platform: Option<Platform>,
+    pub load_address: Option<usize>,
+    pub entry_point: Option<usize>,
+    pub stack_depth: Option<usize>,
+    pub patches: Vec<Patch>,
+    pub gamepad: Option<GamepadMapping>,
+}
+
+// Known ROMs keyed by the SHA-1 of their contents. Each line holds a hash followed by
+// key=value settings, e.g. `<sha1> platform=eti660 load-address=0x600 entry-point=0x600`
+#[derive(Default)]
+pub struct RomDatabase {
+    entries: HashMap<String, RomInfo>,
+}
+
+// Addresses are hex, with or without a 0x prefix
+pub fn parse_address(value: &str) -> Option<usize> {
+    let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+// A stack has room for at least one call
+pub fn parse_stack_depth(value: &str) -> Option<usize> {
+    value.parse().ok().filter(|depth| *depth >= 1)
+}
+
+impl RomDatabase {
+    pub fn load(file_path: &str) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        for (line_number, line) in fs::read_to_string(file_path)?.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", file_path, line_number + 1, message));
+            let mut info = RomInfo::default();
+            for field in &fields[1..] {
+                let (key, value) = field.split_once('=').ok_or_else(|| invalid(format!("expected key=value, got {}", field)))?;
+                match key {
+                    "platform" => info.platform = Some(Platform::from_name(value).ok_or_else(|| invalid(format!("unknown platform {}", value)))?),
+                    "load-address" => info.load_address = Some(parse_address(value).ok_or_else(|| invalid(format!("invalid address {}", value)))?),
+                    "entry-point" => info.entry_point = Some(parse_address(value).ok_or_else(|| invalid(format!("invalid address {}", value)))?),
+                    "stack-depth" => info.stack_depth = Some(parse_stack_depth(value).ok_or_else(|| invalid(format!("invalid stack depth {}", value)))?),
+                    "gamepad" => info.gamepad = Some(GamepadMapping::parse(value).map_err(invalid)?),
+                    "patch" => info.patches.push(Patch::parse(value).map_err(invalid)?),
+                    _ => return Err(invalid(format!("unknown setting {}", key))),
+                }
+            }
+            entries.insert(fields[0].to_ascii_lowercase(), info);
+        }
+        Ok(RomDatabase { entries })
+    }
+
+    pub fn lookup(&self, rom_hash: &str) -> Option<RomInfo> {
+        self.entries.get(rom_hash).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(text: &str) -> io::Result<RomDatabase> {
+        let path = std::env::temp_dir().join(format!("chip8-romdb-test-{}-{}.txt", std::process::id(), text.len()));
+        let path = path.to_str().unwrap();
+        fs::write(path, text).unwrap();
+        let database = RomDatabase::load(path);
+        fs::remove_file(path).unwrap();
+        database
+    }
+
+    #[test]
+    fn addresses_and_stack_depths() {
+        assert_eq!(parse_address("0x600"), Some(0x600));
+        assert_eq!(parse_address("0X2a0"), Some(0x2A0));
+        assert_eq!(parse_address("200"), Some(0x200));
+        assert_eq!(parse_address("0xg00"), None);
+        assert_eq!(parse_stack_depth("12"), Some(12));
+        assert_eq!(parse_stack_depth("0"), None);
+        assert_eq!(parse_stack_depth("-1"), None);
+    }
+
+    #[test]
+    fn entries_are_found_by_hash() {
+        let database = load("# comment\n\nABCDEF platform=eti660 load-address=0x600 entry-point=0x602 stack-depth=16 patch=0x600=00E0\n123456 gamepad=a:5\n").unwrap();
+        let info = database.lookup("abcdef").unwrap();
+        assert_eq!(info.platform, Some(Platform::Eti660));
+        assert_eq!((info.load_address, info.entry_point, info.stack_depth), (Some(0x600), Some(0x602), Some(16)));
+        assert!(matches!(info.patches.as_slice(), [Patch::Bytes { address: 0x600, bytes }] if bytes == &[0x00, 0xE0]));
+        assert!(info.gamepad.is_none());
+        assert!(database.lookup("123456").unwrap().gamepad.is_some());
+        assert!(database.lookup("654321").is_none());
+    }
+
+    #[test]
+    fn bad_lines_name_the_line() {
+        let error = load("abc platform=chip8\nabc platform=c64\n").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains(":2: unknown platform c64"));
+        assert!(load("abc stack-depth=0\n").is_err());
+        assert!(load("abc speed=3\n").is_err());
+        assert!(load("abc platform\n").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn invalid_params(message: &str) -> Self {
+        RpcError { code: -32602, message: message.to_string() }
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError { code: -32601, message: format!("Unknown method {}", method) }
+    }
+
+    // The request made sense but the emulator couldn't do it
+    pub fn failed(message: String) -> Self {
+        RpcError { code: -32000, message }
+    }
+}
+
+pub struct RpcRequest {
+    pub client: usize,
+    id: Value,
+    pub method: String,
+    pub params: Value,
+}
+
+impl RpcRequest {
+    pub fn number(&self, name: &str, default: Option<u64>) -> Result<u64, RpcError> {
+        match &self.params[name] {
+            Value::Null => default.ok_or_else(|| RpcError::invalid_params(&format!("Missing {}", name))),
+            value => value.as_u64().ok_or_else(|| RpcError::invalid_params(&format!("{} must be a positive number", name))),
+        }
+    }
+
+    pub fn boolean(&self, name: &str, default: bool) -> Result<bool, RpcError> {
+        match &self.params[name] {
+            Value::Null => Ok(default),
+            value => value.as_bool().ok_or_else(|| RpcError::invalid_params(&format!("{} must be true or false", name))),
+        }
+    }
+
+    pub fn string(&self, name: &str) -> Result<&str, RpcError> {
+        self.params[name].as_str().ok_or_else(|| RpcError::invalid_params(&format!("{} must be a string", name)))
+    }
+
+    pub fn bytes(&self, name: &str) -> Result<Vec<u8>, RpcError> {
+        let invalid = || RpcError::invalid_params(&format!("{} must be an array of bytes", name));
+        let values = self.params[name].as_array().ok_or_else(invalid)?;
+        values.iter().map(|value| value.as_u64().filter(|byte| *byte <= 0xFF).map(|byte| byte as u8).ok_or_else(invalid)).collect()
+    }
+}
+
+struct RpcClient {
+    reader: BufReader<TcpStream>,
+    line: String,
+}
+
+// JSON-RPC 2.0 over TCP, one request or response per line. Any number of clients can be
+// connected, their requests are handled in the order they arrive
+pub struct RpcServer {
+    listener: TcpListener,
+    clients: Vec<Option<RpcClient>>, // Slots stay put so a request's client index stays valid
+}
+
+impl RpcServer {
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(RpcServer { listener, clients: Vec::new() })
+    }
+
+    // Accepts new clients and returns the requests that came in since the last poll. Malformed
+    // ones are answered right away
+    pub fn poll(&mut self) -> Vec<RpcRequest> {
+        while let Ok((stream, _)) = self.listener.accept() {
+            // Every client gets a read each poll, so a quiet one can only hold the frame up for a millisecond
+            if stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(1)))).is_ok() {
+                let client = RpcClient { reader: BufReader::new(stream), line: String::new() };
+                match self.clients.iter().position(Option::is_none) {
+                    Some(slot) => self.clients[slot] = Some(client),
+                    None => self.clients.push(Some(client)),
+                }
+            }
+        }
+
+        let mut requests = Vec::new();
+        for index in 0..self.clients.len() {
+            let Some(client) = self.clients[index].as_mut() else {
+                continue;
+            };
+            // Partial lines are kept in the client until the rest arrives
+            let line = match client.reader.read_line(&mut client.line) {
+                Ok(0) => {
+                    self.clients[index] = None;
+                    continue;
+                }
+                Ok(_) if client.line.ends_with('\n') => std::mem::take(&mut client.line),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                Err(_) => {
+                    self.clients[index] = None;
+                    continue;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let request: Value = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    self.send(index, json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32700, "message": e.to_string() } }));
+                    continue;
+                }
+            };
+            match request["method"].as_str() {
+                Some(method) => requests.push(RpcRequest {
+                    client: index,
+                    id: request["id"].clone(),
+                    method: method.to_string(),
+                    params: request["params"].clone(),
+                }),
+                None => self.send(index, json!({ "jsonrpc": "2.0", "id": request["id"], "error": { "code": -32600, "message": "Missing method" } })),
+            }
+        }
+        requests
+    }
+
+    pub fn respond(&mut self, request: &RpcRequest, result: Result<Value, RpcError>) {
+        // Notifications don't get an answer
+        if request.id.is_null() {
+            return;
+        }
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+            Err(e) => json!({ "jsonrpc": "2.0", "id": request.id, "error": { "code": e.code, "message": e.message } }),
+        };
+        self.send(request.client, response);
+    }
+
+    fn send(&mut self, client: usize, message: Value) {
+        let Some(Some(connection)) = self.clients.get_mut(client) else {
+            return;
+        };
+        if writeln!(connection.reader.get_mut(), "{}", message).is_err() {
+            self.clients[client] = None;
+        }
+    }
+}
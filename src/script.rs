@@ -0,0 +1,326 @@
+use crate::display::*;
+use crate::memory::*;
+use crate::processor::*;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, AST, INT};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+// What the script works on. The emulator's parts are swapped in for the length of a callback
+// and swapped back out afterwards, so they never have to be shared
+struct Machine {
+    proc: Processor,
+    mem: Memory,
+    display: Display,
+    keypad: u16,
+    held: u16, // Keys pressed by the script, held until it releases them
+    frame: u64,
+}
+
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    instruction: HashMap<usize, Vec<FnPtr>>,
+    write: HashMap<usize, Vec<FnPtr>>,
+    key: Vec<FnPtr>,
+}
+
+// The parts of the emulator a callback gets to see
+pub struct Hardware<'a> {
+    pub proc: &'a mut Processor,
+    pub mem: &'a mut Memory,
+    pub display: &'a mut Display,
+    pub keypad: &'a mut u16,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    machine: Arc<Mutex<Machine>>,
+    hooks: Arc<Mutex<Hooks>>,
+    keypad: u16, // Player's keys last frame, to tell when they change
+    messages: Arc<Mutex<Vec<String>>>, // Printed by the script, for the frontend to show
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Callbacks run on the emulator thread, so one stuck in a loop would hang everything. This is
+// plenty for anything a callback does in a frame
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+fn describe(error: Box<EvalAltResult>) -> String {
+    match error.as_ref() {
+        EvalAltResult::ErrorTooManyOperations(_) => format!("Script ran over {} operations, is it stuck in a loop?", MAX_OPERATIONS),
+        _ => error.to_string(),
+    }
+}
+
+fn check(value: INT, limit: usize, what: &str) -> ScriptResult<usize> {
+    match usize::try_from(value) {
+        Ok(value) if value < limit => Ok(value),
+        _ => Err(format!("{} {} is out of range", what, value).into()),
+    }
+}
+
+impl Script {
+    pub fn load(path: &str) -> Result<Self, String> {
+        Script::compile(&fs::read_to_string(path).map_err(|e| e.to_string())?)
+    }
+
+    // Compiles the script and runs its top level, which is where it registers its callbacks.
+    // Nothing has been loaded into the machine yet at that point
+    fn compile(source: &str) -> Result<Self, String> {
+        let machine = Arc::new(Mutex::new(Machine {
+            proc: Processor::new(0),
+            mem: Memory::new(0, MemoryPolicy::Wrap),
+            display: Display::new(),
+            keypad: 0,
+            held: 0,
+            frame: 0,
+        }));
+        let hooks = Arc::new(Mutex::new(Hooks::default()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let printed = messages.clone();
+        engine.on_print(move |text| printed.lock().unwrap().push(text.to_string()));
+        let printed = messages.clone();
+        engine.on_debug(move |text, _, _| printed.lock().unwrap().push(text.to_string()));
+
+        let h = hooks.clone();
+        engine.register_fn("on_frame", move |callback: FnPtr| h.lock().unwrap().frame.push(callback));
+        let h = hooks.clone();
+        engine.register_fn("on_pc", move |address: INT, callback: FnPtr| h.lock().unwrap().instruction.entry(address as usize).or_default().push(callback));
+        let h = hooks.clone();
+        engine.register_fn("on_write", move |address: INT, callback: FnPtr| h.lock().unwrap().write.entry(address as usize).or_default().push(callback));
+        let h = hooks.clone();
+        engine.register_fn("on_key", move |callback: FnPtr| h.lock().unwrap().key.push(callback));
+
+        let m = machine.clone();
+        engine.register_fn("reg", move |x: INT| -> ScriptResult<INT> { Ok(m.lock().unwrap().proc.registers[check(x, 16, "Register")?] as INT) });
+        let m = machine.clone();
+        engine.register_fn("set_reg", move |x: INT, value: INT| -> ScriptResult<()> {
+            m.lock().unwrap().proc.registers[check(x, 16, "Register")?] = value as u8;
+            Ok(())
+        });
+        let m = machine.clone();
+        engine.register_fn("index", move || m.lock().unwrap().proc.address_register as INT);
+        let m = machine.clone();
+        engine.register_fn("set_index", move |value: INT| m.lock().unwrap().proc.address_register = value as u16);
+        let m = machine.clone();
+        engine.register_fn("pc", move || m.lock().unwrap().proc.program_counter as INT);
+        let m = machine.clone();
+        engine.register_fn("set_pc", move |value: INT| -> ScriptResult<()> {
+            let mut machine = m.lock().unwrap();
+            machine.proc.program_counter = check(value, machine.mem.size(), "Address")?;
+            Ok(())
+        });
+        let m = machine.clone();
+        engine.register_fn("delay_timer", move || m.lock().unwrap().proc.delay_timer.value as INT);
+        let m = machine.clone();
+        engine.register_fn("set_delay_timer", move |value: INT| m.lock().unwrap().proc.delay_timer.value = value as u8);
+        let m = machine.clone();
+        engine.register_fn("sound_timer", move || m.lock().unwrap().proc.sound_timer.value as INT);
+        let m = machine.clone();
+        engine.register_fn("set_sound_timer", move |value: INT| m.lock().unwrap().proc.sound_timer.value = value as u8);
+
+        let m = machine.clone();
+        engine.register_fn("peek", move |address: INT| -> ScriptResult<INT> {
+            let machine = m.lock().unwrap();
+            Ok(machine.mem.peek(check(address, machine.mem.size(), "Address")?) as INT)
+        });
+        let m = machine.clone();
+        engine.register_fn("poke", move |address: INT, value: INT| -> ScriptResult<()> {
+            let mut machine = m.lock().unwrap();
+            let address = check(address, machine.mem.size(), "Address")?;
+            machine.mem.poke(address, value as u8);
+            Ok(())
+        });
+
+        let m = machine.clone();
+        engine.register_fn("screen_width", move || m.lock().unwrap().display.width as INT);
+        let m = machine.clone();
+        engine.register_fn("screen_height", move || m.lock().unwrap().display.height as INT);
+        let m = machine.clone();
+        engine.register_fn("pixel", move |x: INT, y: INT| -> ScriptResult<INT> {
+            let machine = m.lock().unwrap();
+            let display = &machine.display;
+            Ok(display.pixels[check(y, display.height, "Row")? * display.width + check(x, display.width, "Column")?] as INT)
+        });
+
+        let m = machine.clone();
+        engine.register_fn("key_held", move |key: INT| -> ScriptResult<bool> { Ok((m.lock().unwrap().keypad >> check(key, 16, "Key")?) & 1 == 1) });
+        let m = machine.clone();
+        engine.register_fn("press_key", move |key: INT| -> ScriptResult<()> {
+            let mut machine = m.lock().unwrap();
+            let bit = 1 << check(key, 16, "Key")?;
+            machine.held |= bit;
+            machine.keypad |= bit;
+            Ok(())
+        });
+        let m = machine.clone();
+        engine.register_fn("release_key", move |key: INT| -> ScriptResult<()> {
+            let mut machine = m.lock().unwrap();
+            let bit = 1 << check(key, 16, "Key")?;
+            machine.held &= !bit;
+            machine.keypad &= !bit;
+            Ok(())
+        });
+        let m = machine.clone();
+        engine.register_fn("frame", move || m.lock().unwrap().frame as INT);
+
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        engine.run_ast(&ast).map_err(describe)?;
+        Ok(Script { engine, ast, machine, hooks, keypad: 0, messages })
+    }
+
+    pub fn take_messages(&self) -> Vec<String> {
+        std::mem::take(&mut self.messages.lock().unwrap())
+    }
+
+    // Runs the callbacks with the hardware swapped into the machine
+    fn with_hardware(&self, hardware: &mut Hardware, run: impl FnOnce() -> ScriptResult<()>) -> Result<(), String> {
+        let mut swap = |machine: &mut Machine| {
+            std::mem::swap(&mut machine.proc, hardware.proc);
+            std::mem::swap(&mut machine.mem, hardware.mem);
+            std::mem::swap(&mut machine.display, hardware.display);
+            std::mem::swap(&mut machine.keypad, hardware.keypad);
+        };
+        swap(&mut self.machine.lock().unwrap());
+        let result = run();
+        swap(&mut self.machine.lock().unwrap());
+        result.map_err(describe)
+    }
+
+    fn call(&self, callbacks: &[FnPtr], args: impl FuncArgs + Clone) -> ScriptResult<()> {
+        callbacks.iter().try_for_each(|callback| callback.call::<Dynamic>(&self.engine, &self.ast, args.clone()).map(|_| ()))
+    }
+
+    // Called before a frame runs. Tells the script about keys the player pressed or released,
+    // then adds the keys the script is holding
+    pub fn frame_start(&mut self, mut hardware: Hardware) -> Result<(), String> {
+        let keypad = *hardware.keypad;
+        let changed = keypad ^ self.keypad;
+        self.keypad = keypad;
+        let callbacks = self.hooks.lock().unwrap().key.clone();
+        if changed != 0 && !callbacks.is_empty() {
+            self.with_hardware(&mut hardware, || (0..16).filter(|key| (changed >> key) & 1 == 1).try_for_each(|key| self.call(&callbacks, (key as INT, (keypad >> key) & 1 == 1))))?;
+        }
+        *hardware.keypad |= self.machine.lock().unwrap().held;
+        Ok(())
+    }
+
+    // Called once the frame is done and the timers have ticked
+    pub fn frame_end(&self, mut hardware: Hardware) -> Result<(), String> {
+        self.machine.lock().unwrap().frame += 1;
+        let callbacks = self.hooks.lock().unwrap().frame.clone();
+        if callbacks.is_empty() {
+            return Ok(());
+        }
+        self.with_hardware(&mut hardware, || self.call(&callbacks, ()))
+    }
+
+    // Called before the instruction at the address runs
+    pub fn instruction(&self, address: usize, mut hardware: Hardware) -> Result<(), String> {
+        let Some(callbacks) = self.hooks.lock().unwrap().instruction.get(&address).cloned() else {
+            return Ok(());
+        };
+        self.with_hardware(&mut hardware, || self.call(&callbacks, ()))
+    }
+
+    // Called after an instruction with the bytes it wrote
+    pub fn writes(&self, writes: Vec<(usize, u8)>, mut hardware: Hardware) -> Result<(), String> {
+        let hooks = self.hooks.lock().unwrap();
+        let calls: Vec<(Vec<FnPtr>, usize, u8)> = writes.into_iter().filter_map(|(address, value)| hooks.write.get(&address).map(|callbacks| (callbacks.clone(), address, value))).collect();
+        drop(hooks);
+        if calls.is_empty() {
+            return Ok(());
+        }
+        self.with_hardware(&mut hardware, || calls.iter().try_for_each(|(callbacks, address, value)| self.call(callbacks, (*address as INT, *value as INT))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Parts {
+        proc: Processor,
+        mem: Memory,
+        display: Display,
+        keypad: u16,
+    }
+
+    impl Parts {
+        fn new() -> Self {
+            Parts { proc: Processor::new(12), mem: Memory::new(0x1000, MemoryPolicy::Wrap), display: Display::new(), keypad: 0 }
+        }
+
+        fn hardware(&mut self) -> Hardware<'_> {
+            Hardware { proc: &mut self.proc, mem: &mut self.mem, display: &mut self.display, keypad: &mut self.keypad }
+        }
+    }
+
+    fn compile(source: &str) -> Script {
+        Script::compile(source).ok().unwrap()
+    }
+
+    #[test]
+    fn write_callbacks_can_undo_writes() {
+        let script = compile("on_write(0x3F0, |address, value| if value < 3 { poke(address, 3); });");
+        let mut parts = Parts::new();
+        script.writes(vec![(0x3F0, 2), (0x3F1, 0)], parts.hardware()).unwrap();
+        assert_eq!(parts.mem.peek(0x3F0), 3);
+        parts.mem.poke(0x3F0, 5);
+        script.writes(vec![(0x3F0, 5)], parts.hardware()).unwrap();
+        assert_eq!(parts.mem.peek(0x3F0), 5);
+    }
+
+    #[test]
+    fn pc_callbacks_can_move_the_pc() {
+        let script = compile("on_pc(0x200, || set_pc(0x210));");
+        let mut parts = Parts::new();
+        parts.proc.program_counter = 0x202;
+        script.instruction(0x202, parts.hardware()).unwrap();
+        assert_eq!(parts.proc.program_counter, 0x202);
+        script.instruction(0x200, parts.hardware()).unwrap();
+        assert_eq!(parts.proc.program_counter, 0x210);
+        assert!(compile("on_pc(0x200, || set_pc(0x1000));").instruction(0x200, parts.hardware()).is_err());
+    }
+
+    #[test]
+    fn pressed_keys_stay_held() {
+        let mut script = compile("on_frame(|| if frame() == 1 { press_key(5) } else { release_key(5) });");
+        let mut parts = Parts::new();
+        script.frame_start(parts.hardware()).unwrap();
+        script.frame_end(parts.hardware()).unwrap();
+        assert_eq!(parts.keypad, 1 << 5);
+
+        // The player's keys replace the keypad every frame, the script's are added back on
+        parts.keypad = 1 << 2;
+        script.frame_start(parts.hardware()).unwrap();
+        assert_eq!(parts.keypad, 1 << 2 | 1 << 5);
+        script.frame_end(parts.hardware()).unwrap();
+        parts.keypad = 0;
+        script.frame_start(parts.hardware()).unwrap();
+        assert_eq!(parts.keypad, 0);
+    }
+
+    #[test]
+    fn endless_loops_are_stopped() {
+        let script = compile("on_frame(|| { let n = 0; loop { n += 1; } });");
+        let mut parts = Parts::new();
+        assert_eq!(script.frame_end(parts.hardware()).err(), Some(format!("Script ran over {} operations, is it stuck in a loop?", MAX_OPERATIONS)));
+        // Even at the top level
+        assert!(Script::compile("loop {}").err().unwrap().contains("stuck in a loop"));
+    }
+
+    #[test]
+    fn prints_become_messages() {
+        let script = compile(r#"print("loaded"); on_frame(|| debug(frame()));"#);
+        let mut parts = Parts::new();
+        script.frame_end(parts.hardware()).unwrap();
+        assert_eq!(script.take_messages(), vec!["loaded", "1"]);
+        assert!(script.take_messages().is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::config::Config;
+use crate::controller::{Command, Controller, Event, Speed, Status};
+use crate::renderer::Renderer;
+use crate::{create_controller, create_renderer};
+use std::io::{self, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use crossterm::event::{self, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags};
+use crossterm::style::{Color, Print, SetBackgroundColor, SetForegroundColor, ResetColor};
+use crossterm::{cursor, execute, queue, terminal};
+
+// Most terminals only report key presses and repeats, never releases. Without release events a
+// key counts as held for this many frames after it was last seen
+const HOLD_FRAMES: u32 = 8;
+
+// Errors and messages stay on the status line for this long, there's nowhere else to show them
+const MESSAGE_DURATION: Duration = Duration::from_secs(5);
+
+fn hex_key(code: KeyCode) -> Option<usize> {
+    match code {
+        KeyCode::Char(c) => c.to_digit(16).map(|digit| digit as usize),
+        _ => None,
+    }
+}
+
+fn terminal_color(color: macroquad::color::Color) -> Color {
+    Color::Rgb {
+        r: (color.r * 255.0) as u8,
+        g: (color.g * 255.0) as u8,
+        b: (color.b * 255.0) as u8,
+    }
+}
+
+// Every character cell shows two pixel rows: the upper half block is drawn in the top pixel's
+// color on a background of the bottom pixel's color
+fn draw(out: &mut impl Write, renderer: &Renderer) -> io::Result<()> {
+    let colors = renderer.colors().map(terminal_color);
+    let pixels = &renderer.display.pixels;
+    let width = renderer.display.width;
+
+    queue!(out, cursor::MoveTo(0, 0))?;
+    for y in (0..renderer.display.height).step_by(2) {
+        let mut current = None;
+        for x in 0..width {
+            let top = colors[pixels[y * width + x] as usize & 3];
+            let bottom = colors[pixels.get((y + 1) * width + x).copied().unwrap_or(0) as usize & 3];
+            if current != Some((top, bottom)) {
+                queue!(out, SetForegroundColor(top), SetBackgroundColor(bottom))?;
+                current = Some((top, bottom));
+            }
+            queue!(out, Print('▀'))?;
+        }
+        queue!(out, ResetColor, Print("\r\n"))?;
+    }
+    out.flush()
+}
+
+fn run_terminal(renderer: &mut Renderer, controller: &Controller) -> io::Result<()> {
+    // Other threads send their messages as events, nothing else writes to the terminal
+    let mut out = io::stdout();
+    let mut held_frames = [0u32; 16];
+    let mut previous_keys = 0;
+    let mut break_reason = None;
+    let mut halt_reason = String::new();
+    let mut message: Option<(String, Instant)> = None;
+    let mut status = Status {
+        paused: false,
+        halted: false,
+        speed: Speed::Normal,
+        instructions_per_frame: 0,
+    };
+    let frame_duration = Duration::from_secs_f64(1.0 / 60.0);
+
+    // Key release events are only available on terminals supporting the kitty keyboard protocol
+    let release_events = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if release_events {
+        execute!(out, event::PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+    }
+
+    loop {
+        let frame_start = Instant::now();
+        for held in held_frames.iter_mut() {
+            if !release_events {
+                *held = held.saturating_sub(1);
+            }
+        }
+
+        while event::poll(Duration::ZERO)? {
+            let event::Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.code == KeyCode::Esc || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)) {
+                if release_events {
+                    execute!(out, event::PopKeyboardEnhancementFlags)?;
+                }
+                return Ok(());
+            }
+            if key.kind == KeyEventKind::Press {
+                match key.code {
+                    KeyCode::Char('p') => {
+                        renderer.next_palette();
+                    }
+                    KeyCode::Char(' ') => controller.send(if status.paused { Command::Resume } else { Command::Pause }),
+                    KeyCode::Backspace => controller.send(Command::Reset),
+                    KeyCode::Char('n') if status.paused => controller.send(Command::AdvanceFrame),
+                    KeyCode::Char(']') => controller.send(Command::SetSpeed(status.speed.next_fast_forward())),
+                    KeyCode::Char('[') => controller.send(Command::SetSpeed(status.speed.next_slow_motion())),
+                    KeyCode::Char('+') | KeyCode::Char('=') => {
+                        controller.send(Command::SetInstructionsPerFrame(status.instructions_per_frame + (status.instructions_per_frame / 10).max(1)));
+                    }
+                    KeyCode::Char('-') => {
+                        controller.send(Command::SetInstructionsPerFrame(status.instructions_per_frame.saturating_sub((status.instructions_per_frame / 10).max(1))));
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(hex_key) = hex_key(key.code) {
+                held_frames[hex_key] = match key.kind {
+                    KeyEventKind::Release => 0,
+                    _ if release_events => u32::MAX,
+                    _ => HOLD_FRAMES,
+                };
+            }
+        }
+
+        let mut keys = 0;
+        for (hex_key, held) in held_frames.iter().enumerate() {
+            if *held > 0 {
+                keys |= 1 << hex_key;
+            }
+        }
+        if keys != previous_keys {
+            controller.send(Command::SetKeys(keys));
+            previous_keys = keys;
+        }
+
+        for event in controller.events() {
+            match event {
+                Event::FrameReady(display) => renderer.present(&display),
+                Event::StatusChanged(new_status) => status = new_status,
+                // The terminal bell is the closest thing to a buzzer, ring it when the sound starts
+                Event::BeepOn => queue!(out, Print('\x07'))?,
+                Event::BeepOff => {}
+                // Anything printed would be drawn over, so these go on the status line
+                Event::Halted(reason) => halt_reason = reason,
+                Event::Error(text) | Event::Message(text) => message = Some((text, Instant::now())),
+                Event::Break(reason) => break_reason = Some(reason),
+                Event::CallStack(_) | Event::PolledKeys(_) => {}
+            }
+        }
+        draw(&mut out, renderer)?;
+        let mut status_line = format!("{} IPF  {}", status.instructions_per_frame, status.speed.name());
+        if status.halted {
+            status_line.push_str(&format!("  HALTED: {}", halt_reason));
+        } else if status.paused {
+            status_line.push_str("  PAUSED");
+            if let Some(reason) = &break_reason {
+                status_line.push_str(&format!(": {}", reason));
+            }
+        } else {
+            break_reason = None;
+        }
+        if let Some((error, _)) = message.as_ref().filter(|(_, shown)| shown.elapsed() < MESSAGE_DURATION) {
+            status_line.push_str(&format!("  {}", error));
+        }
+        queue!(out, terminal::Clear(terminal::ClearType::CurrentLine), Print(status_line))?;
+        out.flush()?;
+
+        sleep(frame_duration.saturating_sub(frame_start.elapsed()));
+    }
+}
+
+pub fn run(config: Config) {
+    let mut renderer = create_renderer(&config);
+    let mut controller = create_controller(&config);
+
+    let result = terminal::enable_raw_mode()
+        .and_then(|_| execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide))
+        .and_then(|_| run_terminal(&mut renderer, &controller));
+
+    // Always give the terminal back in a usable state, before the emulator thread gets to print
+    // anything while shutting down
+    let _ = execute!(io::stdout(), ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    controller.shutdown();
+    if let Err(e) = result {
+        eprintln!("Terminal error: {}", e);
+    }
+}
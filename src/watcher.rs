@@ -0,0 +1,69 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// A file that can't be loaded is tried again for about a second before giving up, in case it
+// was caught in the middle of being written
+const MAX_RETRIES: u32 = 4;
+
+// Polls a file's modification time. A change is only reported once the file stopped changing
+// for a poll interval, so an assembler still writing the ROM doesn't get it loaded half done
+pub struct FileWatcher {
+    pub path: String,
+    modified: Option<SystemTime>,
+    pending: Option<SystemTime>,
+    last_poll: Instant,
+    retries: u32, // Failed loads of the current version of the file
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+impl FileWatcher {
+    pub fn new(path: &str) -> Self {
+        FileWatcher {
+            path: path.to_string(),
+            modified: modified_time(path),
+            pending: None,
+            last_poll: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    pub fn has_changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        let modified = modified_time(&self.path);
+        if self.retries > 0 && modified == self.modified {
+            return true;
+        }
+        if modified.is_none() || modified == self.modified {
+            self.pending = None;
+            return false;
+        }
+        if self.pending != modified {
+            self.pending = modified;
+            return false;
+        }
+        self.modified = modified;
+        self.pending = None;
+        self.retries = 0;
+        true
+    }
+
+    // Call after the changed file loaded fine, or couldn't be. Returns true when it's worth
+    // trying again on the next poll, false once the error should be reported
+    pub fn reloaded(&mut self, success: bool) -> bool {
+        if success || self.retries >= MAX_RETRIES {
+            self.retries = 0;
+            return false;
+        }
+        self.retries += 1;
+        true
+    }
+}